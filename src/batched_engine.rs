@@ -1,78 +1,595 @@
 use anyhow::Result;
 use async_trait::async_trait;
-use std::collections::VecDeque;
+use metrics::{counter, gauge, histogram};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use tokio::sync::oneshot;
-use tokio::time::{Instant, interval};
+use tokio::time::{Instant, interval, timeout_at};
 
-use crate::config::BatchConfig;
-use crate::engine::BatchedEngine;
-use crate::engine::Engine;
-use crate::types::{ClassificationRequest, ClassificationResponse};
+use crate::config::{BatchConfig, Dispatch};
+use crate::engine::{BatchRequest, BatchedEngine, WorkItem};
+use crate::engine::{Engine, EngineError, TokenClassificationEngine};
+use crate::types::{
+    ClassificationRequest, ClassificationResponse, TokenClassificationRequest,
+    TokenClassificationResponse,
+};
 
-type ResponseSender = oneshot::Sender<Result<ClassificationResponse>>;
+type ResponseSender<Resp> = oneshot::Sender<Result<Resp>>;
 
+struct QueuedRequest<T: BatchedEngine> {
+    request: T::Request,
+    /// Each input of the request, tokenized by the caller
+    tokenized: Vec<T::Tokenized>,
+    response_tx: ResponseSender<T::Response>,
+    deadline: Option<Instant>,
+    slot: QueueSlot,
+}
+
+/// Reserves room for a request's inputs in the queue; released as the inputs
+/// are taken into batches, and on drop for those abandoned.
 #[derive(Debug)]
-struct QueuedRequest {
-    request: ClassificationRequest,
-    response_tx: ResponseSender,
+struct QueueSlot {
+    queued_inputs: Arc<AtomicUsize>,
+    inputs: usize,
+}
+
+impl QueueSlot {
+    /// Reserves room for `inputs` inputs, failing if the queue is full, or
+    /// if the request could never fit in it.
+    fn acquire(
+        queued_inputs: &Arc<AtomicUsize>,
+        inputs: usize,
+        limit: Option<usize>,
+    ) -> Result<Self, EngineError> {
+        if let Some(limit) = limit
+            && inputs > limit
+        {
+            return Err(EngineError::TooManyInputs { inputs, limit });
+        }
+
+        let previous = queued_inputs.fetch_add(inputs, Ordering::AcqRel);
+        let slot = Self {
+            queued_inputs: queued_inputs.clone(),
+            inputs,
+        };
+
+        match limit {
+            Some(limit) if previous + inputs > limit => Err(EngineError::Overloaded),
+            _ => Ok(slot),
+        }
+    }
+
+    fn release(&mut self, inputs: usize) {
+        let inputs = inputs.min(self.inputs);
+        self.inputs -= inputs;
+        self.queued_inputs.fetch_sub(inputs, Ordering::AcqRel);
+    }
+}
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        self.queued_inputs.fetch_sub(self.inputs, Ordering::AcqRel);
+    }
+}
+
+/// A request whose inputs are queued or being processed, collecting their
+/// outputs until the last one is done.
+struct Job<T: BatchedEngine> {
+    request: Arc<T::Request>,
+    response_tx: ResponseSender<T::Response>,
+    deadline: Option<Instant>,
+    slot: QueueSlot,
+    outputs: Vec<Option<T::Output>>,
+    /// Number of inputs without an output yet
+    remaining: usize,
+}
+
+/// An input waiting in the processor's queue, with its encoded length.
+struct PendingItem<T: BatchedEngine> {
+    job: u64,
+    item: WorkItem<T::Request>,
+    tokenized: T::Tokenized,
+    /// Number of sequences the input encodes to
+    sequences: usize,
+    /// Length in tokens of the input's longest sequence
+    max_tokens: usize,
+    enqueued_at: Instant,
 }
 
-pub struct BatchedEngineWrapper {
-    request_tx: flume::Sender<QueuedRequest>,
+pub struct BatchedEngineWrapper<T: BatchedEngine> {
+    request_tx: flume::Sender<QueuedRequest<T>>,
+    /// The first worker's engine, used to tokenize requests before they are
+    /// queued
+    engine: Arc<T>,
+    default_timeout: Option<Duration>,
+    /// Inputs sent to the processor but not yet taken into a batch
+    queued_inputs: Arc<AtomicUsize>,
+    max_queue_size: Option<usize>,
 }
 
-impl BatchedEngineWrapper {
-    pub fn new<T: BatchedEngine + 'static>(
-        config: BatchConfig,
-        batched_engine: T,
-    ) -> (Self, BatchProcessor<T>) {
+impl<T: BatchedEngine + 'static> BatchedEngineWrapper<T> {
+    /// Creates the front end of model `model` and the processor batching its
+    /// requests over `workers`, one engine per batch worker.
+    pub fn new(config: BatchConfig, model: String, workers: Vec<T>) -> (Self, BatchProcessor<T>) {
+        assert!(!workers.is_empty(), "a model needs at least one worker");
+        let workers: Vec<Arc<T>> = workers.into_iter().map(Arc::new).collect();
         let (request_tx, request_rx) = flume::bounded(0); // Rendezvous channel
+        let default_timeout = config.default_timeout;
+        let max_queue_size = config.max_queue_size;
 
+        let buckets = (0..=config.length_buckets.len())
+            .map(|_| VecDeque::new())
+            .collect();
+
+        let batched_engine = workers[0].clone();
         let processor = BatchProcessor {
             request_rx,
             config,
-            request_queue: VecDeque::new(),
-            batched_engine,
+            buckets,
+            jobs: HashMap::new(),
+            next_job_id: 0,
+            model,
+            batched_engine: batched_engine.clone(),
+            workers,
         };
 
-        let engine = Self { request_tx };
+        let engine = Self {
+            request_tx,
+            engine: batched_engine,
+            default_timeout,
+            queued_inputs: Arc::new(AtomicUsize::new(0)),
+            max_queue_size,
+        };
 
         (engine, processor)
     }
+
+    /// Tokenizes `request` on a blocking thread, queues it for the batch
+    /// processor and waits for its result.
+    #[tracing::instrument(skip(self, request), fields(input_count = request.input_len()))]
+    pub async fn submit(&self, request: T::Request) -> Result<T::Response> {
+        let slot = QueueSlot::acquire(
+            &self.queued_inputs,
+            request.input_len(),
+            self.max_queue_size,
+        )
+        .inspect_err(|err| {
+            if matches!(err, EngineError::Overloaded) {
+                counter!("classification_requests_rejected_total").increment(1);
+            }
+        })?;
+
+        let (response_tx, response_rx) = oneshot::channel();
+
+        let deadline = request
+            .timeout_ms()
+            .map(Duration::from_millis)
+            .or(self.default_timeout)
+            .map(|timeout| Instant::now() + timeout);
+
+        let response = async {
+            let engine = self.engine.clone();
+            let (request, tokenized) = run_blocking(move || {
+                let tokenized = (0..request.input_len())
+                    .map(|index| engine.tokenize(&request, index))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok((request, tokenized))
+            })
+            .await
+            .inspect_err(|err| {
+                tracing::debug!(error = %err, "Rejecting request that cannot be encoded");
+            })?;
+
+            let queued_request = QueuedRequest {
+                request,
+                tokenized,
+                response_tx,
+                deadline,
+                slot,
+            };
+            self.request_tx
+                .send_async(queued_request)
+                .await
+                .map_err(|_| EngineError::Unavailable)?;
+
+            response_rx.await.map_err(|_| EngineError::Unavailable)?
+        };
+
+        match deadline {
+            Some(deadline) => timeout_at(deadline, response)
+                .await
+                .map_err(|_| EngineError::DeadlineExceeded)?,
+            None => response.await,
+        }
+    }
 }
 
 #[async_trait]
-impl Engine for BatchedEngineWrapper {
-    #[tracing::instrument(skip(self, request), fields(input_count = request.input.len()))]
+impl<T> Engine for BatchedEngineWrapper<T>
+where
+    T: BatchedEngine<Request = ClassificationRequest, Response = ClassificationResponse> + 'static,
+{
     async fn classify(&self, request: ClassificationRequest) -> Result<ClassificationResponse> {
-        let (response_tx, response_rx) = oneshot::channel();
+        self.submit(request).await
+    }
+}
 
-        let queued_request = QueuedRequest {
-            request,
-            response_tx,
-        };
+#[async_trait]
+impl<T> TokenClassificationEngine for BatchedEngineWrapper<T>
+where
+    T: BatchedEngine<Request = TokenClassificationRequest, Response = TokenClassificationResponse>
+        + 'static,
+{
+    async fn classify_tokens(
+        &self,
+        request: TokenClassificationRequest,
+    ) -> Result<TokenClassificationResponse> {
+        self.submit(request).await
+    }
+}
 
-        self.request_tx
-            .send_async(queued_request)
-            .await
-            .map_err(|_| anyhow::anyhow!("Engine queue is closed"))?;
+/// A batch on its way through the pipeline, with the job and input index of
+/// each of its items, and their tokenized inputs until it is encoded.
+struct Batch<T: BatchedEngine> {
+    positions: Vec<(u64, usize)>,
+    items: Arc<[WorkItem<T::Request>]>,
+    tokenized: Vec<T::Tokenized>,
+}
 
-        response_rx
-            .await
-            .map_err(|_| anyhow::anyhow!("Response channel closed"))?
+/// A batch passed from the encode stage to the forward stage.
+type EncodedBatch<T> = (Batch<T>, <T as BatchedEngine>::Encoded);
+
+/// A batch passed from the forward stage to the post-process stage.
+type ForwardedBatch<T> = (
+    Batch<T>,
+    <T as BatchedEngine>::Encoded,
+    <T as BatchedEngine>::Forwarded,
+);
+
+/// The outputs of a batch, or the error that failed it, sent back to the
+/// scheduler by the worker that ran it.
+struct Completed<T: BatchedEngine> {
+    worker: usize,
+    positions: Vec<(u64, usize)>,
+    outputs: Result<Vec<T::Output>>,
+}
+
+/// Hands batches to the workers of a model, each through its own bounded
+/// channel, following the dispatch policy.
+struct Dispatcher<T: BatchedEngine> {
+    model: String,
+    policy: Dispatch,
+    senders: Vec<flume::Sender<Batch<T>>>,
+    /// Batches sent to each worker and not yet completed
+    in_flight: Vec<usize>,
+    /// Worker to try first, so that turns rotate
+    next: usize,
+}
+
+impl<T: BatchedEngine> Dispatcher<T> {
+    /// The worker to send the next batch to, if any has room for it. Ties
+    /// between least loaded workers go to the next one in turn.
+    fn pick(&self) -> Option<usize> {
+        let workers = self.senders.len();
+        let mut candidates = (0..workers)
+            .map(|offset| (self.next + offset) % workers)
+            .filter(|&worker| !self.senders[worker].is_full());
+        match self.policy {
+            Dispatch::RoundRobin => candidates.next(),
+            Dispatch::LeastLoaded => candidates.min_by_key(|&worker| self.in_flight[worker]),
+        }
+    }
+
+    /// Sends `batch` to `worker`, handing it back if the worker can't take
+    /// it.
+    fn send(&mut self, worker: usize, batch: Batch<T>) -> Result<(), Batch<T>> {
+        self.next = (worker + 1) % self.senders.len();
+        self.senders[worker]
+            .try_send(batch)
+            .map_err(flume::TrySendError::into_inner)?;
+        self.in_flight[worker] += 1;
+        self.record_in_flight(worker);
+        Ok(())
+    }
+
+    fn completed(&mut self, worker: usize) {
+        self.in_flight[worker] = self.in_flight[worker].saturating_sub(1);
+        self.record_in_flight(worker);
+    }
+
+    fn record_in_flight(&self, worker: usize) {
+        gauge!(
+            "batch_worker_in_flight",
+            "model" => self.model.clone(),
+            "worker" => worker.to_string()
+        )
+        .set(self.in_flight[worker] as f64);
     }
 }
 
 pub struct BatchProcessor<T: BatchedEngine> {
-    request_rx: flume::Receiver<QueuedRequest>,
+    request_rx: flume::Receiver<QueuedRequest<T>>,
     config: BatchConfig,
-    request_queue: VecDeque<QueuedRequest>,
-    batched_engine: T,
+    /// One FIFO queue of inputs per length bucket; a single queue when
+    /// bucketing is off
+    buckets: Vec<VecDeque<PendingItem<T>>>,
+    /// Requests with inputs still queued or being processed, by id
+    jobs: HashMap<u64, Job<T>>,
+    next_job_id: u64,
+    /// Name of the model, for metrics
+    model: String,
+    /// The first worker's engine, used to measure inputs and assemble
+    /// responses
+    batched_engine: Arc<T>,
+    /// Engine of each batch worker
+    workers: Vec<Arc<T>>,
 }
 
-impl<T: BatchedEngine> BatchProcessor<T> {
+impl<T: BatchedEngine + 'static> BatchProcessor<T> {
+    /// Queues each input of a request in its length bucket and returns the
+    /// buckets it went to.
+    fn enqueue(&mut self, queued: QueuedRequest<T>) -> Vec<usize> {
+        let input_len = queued.request.input_len();
+        if input_len == 0 {
+            let response = self.batched_engine.respond(&queued.request, Vec::new());
+            let _ = queued.response_tx.send(Ok(response));
+            return Vec::new();
+        }
+
+        let job = self.next_job_id;
+        self.next_job_id += 1;
+        let request = Arc::new(queued.request);
+        let enqueued_at = Instant::now();
+
+        let mut buckets = Vec::new();
+        for (index, tokenized) in queued.tokenized.into_iter().enumerate() {
+            let lengths = self.batched_engine.sequence_lengths(&tokenized);
+            let max_tokens = lengths.iter().copied().max().unwrap_or(0);
+            let bucket = self
+                .config
+                .length_buckets
+                .partition_point(|&boundary| boundary < max_tokens);
+
+            self.buckets[bucket].push_back(PendingItem {
+                job,
+                item: WorkItem {
+                    request: request.clone(),
+                    index,
+                },
+                tokenized,
+                sequences: lengths.len(),
+                max_tokens,
+                enqueued_at,
+            });
+            if !buckets.contains(&bucket) {
+                buckets.push(bucket);
+            }
+        }
+
+        self.jobs.insert(
+            job,
+            Job {
+                request,
+                response_tx: queued.response_tx,
+                deadline: queued.deadline,
+                slot: queued.slot,
+                outputs: (0..input_len).map(|_| None).collect(),
+                remaining: input_len,
+            },
+        );
+
+        buckets
+    }
+
+    fn queue_len(&self) -> usize {
+        self.buckets.iter().map(VecDeque::len).sum()
+    }
+
+    /// Drops requests whose caller has gone away, e.g. because the HTTP client
+    /// disconnected, and rejects those past their deadline, so that their
+    /// queued inputs never reach the model.
+    fn purge_stale(&mut self) {
+        let now = Instant::now();
+        let mut cancelled_inputs = 0;
+        let mut expired_inputs = 0;
+
+        // Check the deadline first: the caller stops waiting once it passes,
+        // which would otherwise look like a disconnect.
+        let expired: Vec<u64> = self
+            .jobs
+            .iter()
+            .filter(|(_, job)| job.deadline.is_some_and(|deadline| deadline <= now))
+            .map(|(&id, _)| id)
+            .collect();
+        for id in expired {
+            let job = self.jobs.remove(&id).expect("job is queued");
+            expired_inputs += job.slot.inputs;
+            let _ = job
+                .response_tx
+                .send(Err(EngineError::DeadlineExceeded.into()));
+        }
+
+        let cancelled: Vec<u64> = self
+            .jobs
+            .iter()
+            .filter(|(_, job)| job.response_tx.is_closed())
+            .map(|(&id, _)| id)
+            .collect();
+        for id in cancelled {
+            let job = self.jobs.remove(&id).expect("job is queued");
+            cancelled_inputs += job.slot.inputs;
+        }
+
+        if cancelled_inputs > 0 {
+            tracing::debug!(cancelled_inputs, "Dropped cancelled requests");
+            counter!("classification_inputs_cancelled_total").increment(cancelled_inputs as u64);
+        }
+        if expired_inputs > 0 {
+            tracing::debug!(expired_inputs, "Rejected expired requests");
+            counter!("classification_inputs_expired_total").increment(expired_inputs as u64);
+        }
+
+        self.drop_orphaned_items();
+    }
+
+    /// Removes queued inputs of requests that are no longer waiting, because
+    /// they were dropped or another of their inputs failed.
+    fn drop_orphaned_items(&mut self) {
+        for queue in &mut self.buckets {
+            queue.retain(|pending| self.jobs.contains_key(&pending.job));
+        }
+    }
+
+    /// The bucket holding the longest-waiting input, so no bucket starves.
+    fn oldest_bucket(&self) -> Option<usize> {
+        self.oldest_bucket_where(|_| true)
+    }
+
+    /// The bucket holding the longest-waiting input among those matching
+    /// `filter`.
+    fn oldest_bucket_where(&self, filter: impl Fn(usize) -> bool) -> Option<usize> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|&(index, _)| filter(index))
+            .filter_map(|(index, queue)| queue.front().map(|pending| (index, pending.enqueued_at)))
+            .min_by_key(|&(_, enqueued_at)| enqueued_at)
+            .map(|(index, _)| index)
+    }
+
+    /// Number of inputs at the front of `bucket` that fit in the next batch.
+    /// A batch holds at most `batch_size` inputs and, if a token budget is
+    /// set, at most `max_batch_tokens` padded tokens. The first input is
+    /// always taken so that an oversized input cannot stall the queue.
+    fn next_batch_len(&self, bucket: usize) -> usize {
+        let queue = &self.buckets[bucket];
+        let limit = self.config.batch_size.min(queue.len());
+        let Some(max_batch_tokens) = self.config.max_batch_tokens else {
+            return limit;
+        };
+
+        let mut sequences = 0;
+        let mut longest = 0;
+        for (index, pending) in queue.iter().take(limit).enumerate() {
+            let next_sequences = sequences + pending.sequences;
+            let next_longest = longest.max(pending.max_tokens);
+            if index > 0 && next_sequences * next_longest > max_batch_tokens {
+                return index;
+            }
+            sequences = next_sequences;
+            longest = next_longest;
+        }
+
+        limit
+    }
+
+    /// Whether `bucket` already holds a full batch, by count or by tokens.
+    fn batch_ready(&self, bucket: usize) -> bool {
+        let queue_len = self.buckets[bucket].len();
+        queue_len >= self.config.batch_size || self.next_batch_len(bucket) < queue_len
+    }
+
+    /// Takes the next batch of `bucket` off the queue, in FIFO order.
+    fn next_batch(&mut self, bucket: usize) -> Option<Batch<T>> {
+        self.purge_stale();
+
+        let batch_len = self.next_batch_len(bucket);
+        if batch_len == 0 {
+            return None;
+        }
+        let mut positions = Vec::with_capacity(batch_len);
+        let mut items = Vec::with_capacity(batch_len);
+        let mut tokenized = Vec::with_capacity(batch_len);
+        for pending in self.buckets[bucket].drain(..batch_len) {
+            positions.push((pending.job, pending.item.index));
+            items.push(pending.item);
+            tokenized.push(pending.tokenized);
+        }
+
+        for &(job, _) in &positions {
+            if let Some(job) = self.jobs.get_mut(&job) {
+                job.slot.release(1);
+            }
+        }
+
+        tracing::debug!(batch_size = items.len(), bucket, "Dispatching batch");
+        Some(Batch {
+            positions,
+            items: items.into(),
+            tokenized,
+        })
+    }
+
+    /// Sends full batches to the workers for as long as one has room, taking
+    /// the bucket whose front input has waited longest first, so that long
+    /// inputs still get a worker under a steady stream of short ones.
+    fn dispatch_ready(&mut self, dispatcher: &mut Dispatcher<T>) {
+        while let Some(bucket) = self.oldest_bucket_where(|bucket| self.batch_ready(bucket)) {
+            let Some(worker) = dispatcher.pick() else {
+                return;
+            };
+            if let Some(batch) = self.next_batch(bucket) {
+                self.send_batch(dispatcher, worker, batch);
+            }
+        }
+    }
+
+    /// Sends `batch` to `worker`, failing its requests if the worker has
+    /// stopped.
+    fn send_batch(&mut self, dispatcher: &mut Dispatcher<T>, worker: usize, batch: Batch<T>) {
+        if let Err(batch) = dispatcher.send(worker, batch) {
+            tracing::error!(worker, "Batch worker stopped, failing its batch");
+            self.fail_jobs(&batch.positions, || EngineError::Unavailable.into());
+        }
+    }
+
     #[tracing::instrument(skip(self))]
     pub async fn run_forever(mut self) -> Result<()> {
+        let depth = self.config.pipeline_depth.max(1);
+        let (completed_tx, completed_rx) = flume::unbounded();
+        let mut dispatcher = Dispatcher {
+            model: self.model.clone(),
+            policy: self.config.dispatch,
+            senders: Vec::with_capacity(self.workers.len()),
+            in_flight: vec![0; self.workers.len()],
+            next: 0,
+        };
+
+        // Every worker runs its own pipeline, fed by the dispatcher
+        for (index, engine) in self.workers.iter().enumerate() {
+            let worker = Worker {
+                model: self.model.clone(),
+                index,
+                engine: engine.clone(),
+            };
+            let (batch_tx, batch_rx) = flume::bounded(depth);
+            let (encoded_tx, encoded_rx) = flume::bounded(depth);
+            let (forwarded_tx, forwarded_rx) = flume::bounded(depth);
+            dispatcher.senders.push(batch_tx);
+
+            tracing::info!(model = %self.model, worker = index, "Starting batch worker");
+            tokio::spawn(encode_stage(
+                worker.clone(),
+                batch_rx,
+                encoded_tx,
+                completed_tx.clone(),
+            ));
+            tokio::spawn(forward_stage(
+                worker.clone(),
+                encoded_rx,
+                forwarded_tx,
+                completed_tx.clone(),
+            ));
+            tokio::spawn(postprocess_stage(
+                worker,
+                forwarded_rx,
+                completed_tx.clone(),
+            ));
+        }
+        drop(completed_tx);
+
         let mut tick_timer = interval(self.config.tick_duration);
 
         loop {
@@ -81,90 +598,669 @@ impl<T: BatchedEngine> BatchProcessor<T> {
                 request = self.request_rx.recv_async() => {
                     match request {
                         Ok(req) => {
-                            self.request_queue.push_back(req);
-                            tracing::debug!(queue_size = self.request_queue.len(), "Request received and queued");
-
-                            // If we have enough requests, process a batch immediately
-                            if self.request_queue.len() >= self.config.batch_size {
-                                tracing::debug!(batch_size = self.config.batch_size, "Batch size reached, processing immediately");
-                                self.process_batch().await;
+                            let buckets = self.enqueue(req);
+                            if buckets.is_empty() {
+                                continue;
                             }
+                            tracing::debug!(queue_size = self.queue_len(), ?buckets, "Request received and queued");
+
+                            // If we have enough inputs, dispatch batches immediately
+                            self.dispatch_ready(&mut dispatcher);
                         }
                         Err(_) => {
                             tracing::info!("Channel closed, processing remaining requests and exiting");
-                            // Channel closed, process remaining requests and exit
-                            if !self.request_queue.is_empty() {
-                                self.process_batch().await;
-                            }
-                            break Ok(());
+                            break;
                         }
                     }
                 }
 
-                // Tick timer - process pending requests even if batch isn't full
+                // Batches coming out of the workers
+                completed = completed_rx.recv_async() => {
+                    let Ok(completed) = completed else {
+                        anyhow::bail!("Batch workers stopped");
+                    };
+                    dispatcher.completed(completed.worker);
+                    self.complete_batch(completed);
+                    self.dispatch_ready(&mut dispatcher);
+                }
+
+                // Tick timer - dispatch pending inputs even if batch isn't full
                 _ = tick_timer.tick() => {
-                    if !self.request_queue.is_empty() {
-                        tracing::debug!(pending_requests = self.request_queue.len(), "Tick timer fired, processing pending requests");
-                        self.process_batch().await;
-                    } else {
-                        tracing::trace!("Tick timer fired but no pending requests");
+                    match (self.oldest_bucket(), dispatcher.pick()) {
+                        (Some(bucket), Some(worker)) => {
+                            tracing::debug!(pending_inputs = self.queue_len(), bucket, worker, "Tick timer fired, dispatching pending inputs");
+                            if let Some(batch) = self.next_batch(bucket) {
+                                self.send_batch(&mut dispatcher, worker, batch);
+                            }
+                        }
+                        (Some(_), None) => tracing::trace!("Tick timer fired but every worker is busy"),
+                        (None, _) => tracing::trace!("Tick timer fired but no pending requests"),
                     }
                 }
             }
         }
+
+        // Channel closed, process remaining requests and exit once the
+        // workers have drained
+        while let Some(bucket) = self.oldest_bucket() {
+            if let Some(worker) = dispatcher.pick() {
+                if let Some(batch) = self.next_batch(bucket) {
+                    self.send_batch(&mut dispatcher, worker, batch);
+                }
+            } else if let Ok(completed) = completed_rx.recv_async().await {
+                dispatcher.completed(completed.worker);
+                self.complete_batch(completed);
+            } else {
+                break;
+            }
+        }
+        drop(dispatcher);
+        while let Ok(completed) = completed_rx.recv_async().await {
+            self.complete_batch(completed);
+        }
+        Ok(())
     }
 
-    #[tracing::instrument(skip(self))]
-    async fn process_batch(&mut self) {
-        let batch_start = Instant::now();
+    /// Records the outputs of a batch that went through a worker, or fails
+    /// every request with an input in it.
+    fn complete_batch(&mut self, completed: Completed<T>) {
+        match completed.outputs {
+            Ok(outputs) => {
+                tracing::debug!(
+                    output_count = outputs.len(),
+                    worker = completed.worker,
+                    "Batch processing successful"
+                );
+                for ((job, index), output) in completed.positions.into_iter().zip(outputs) {
+                    self.complete_input(job, index, output);
+                }
+            }
+            Err(err) => {
+                tracing::error!(
+                    worker = completed.worker,
+                    "Batch processing failed: {}",
+                    err
+                );
+                self.fail_jobs(&completed.positions, || {
+                    anyhow::anyhow!("Batch processing failed: {}", err)
+                });
+            }
+        }
+    }
 
-        // Take up to batch_size requests in FIFO order
-        let batch: Vec<_> = self
-            .request_queue
-            .drain(..self.config.batch_size.min(self.request_queue.len()))
-            .collect();
+    /// Fails every request with an input at `positions`, dropping the rest
+    /// of their queued inputs.
+    fn fail_jobs(&mut self, positions: &[(u64, usize)], err: impl Fn() -> anyhow::Error) {
+        for (job, _) in positions {
+            if let Some(job) = self.jobs.remove(job) {
+                let _ = job.response_tx.send(Err(err()));
+            }
+        }
+        self.drop_orphaned_items();
+    }
 
-        if batch.is_empty() {
+    /// Records the output of input `index` of `job`, and sends the response
+    /// once the request's last input is done.
+    fn complete_input(&mut self, job: u64, index: usize, output: T::Output) {
+        let Some(pending) = self.jobs.get_mut(&job) else {
+            return;
+        };
+        pending.outputs[index] = Some(output);
+        pending.remaining -= 1;
+        if pending.remaining > 0 {
             return;
         }
 
-        tracing::info!(batch_size = batch.len(), "Processing batch");
+        let job = self.jobs.remove(&job).expect("job is queued");
+        let outputs = job
+            .outputs
+            .into_iter()
+            .map(|output| output.expect("every input has an output"))
+            .collect();
+        let response = self.batched_engine.respond(&job.request, outputs);
+        let _ = job.response_tx.send(Ok(response));
+    }
+}
 
-        // Extract requests and response channels
-        let requests: Vec<_> = batch.iter().map(|req| req.request.clone()).collect();
-        let response_channels: Vec<_> = batch.into_iter().map(|req| req.response_tx).collect();
+/// Runs `stage` on a blocking thread, turning a panic into an error.
+async fn run_blocking<R: Send + 'static>(
+    stage: impl FnOnce() -> Result<R> + Send + 'static,
+) -> Result<R> {
+    tokio::task::spawn_blocking(stage).await?
+}
 
-        // Process batch through the batched engine
-        tracing::debug!("Calling classify_batch on engine");
-        let responses = self.batched_engine.classify_batch(requests).await;
+/// One of the batch workers of a model, running batches through its own copy
+/// of the model.
+struct Worker<T> {
+    model: String,
+    index: usize,
+    engine: Arc<T>,
+}
 
-        // Send responses back
-        match responses {
-            Ok(response_vec) => {
-                tracing::debug!(
-                    response_count = response_vec.len(),
-                    "Batch processing successful"
-                );
-                for (response_tx, response_result) in
-                    response_channels.into_iter().zip(response_vec.into_iter())
+impl<T> Clone for Worker<T> {
+    fn clone(&self) -> Self {
+        Self {
+            model: self.model.clone(),
+            index: self.index,
+            engine: self.engine.clone(),
+        }
+    }
+}
+
+impl<T> Worker<T> {
+    fn record_stage(&self, stage: &'static str, start: Instant) {
+        histogram!(
+            "batch_stage_duration_seconds",
+            "model" => self.model.clone(),
+            "worker" => self.index.to_string(),
+            "stage" => stage
+        )
+        .record(start.elapsed().as_secs_f64());
+    }
+}
+
+/// First stage of the pipeline: pads tokenized batches on the CPU.
+async fn encode_stage<T: BatchedEngine + 'static>(
+    worker: Worker<T>,
+    batch_rx: flume::Receiver<Batch<T>>,
+    encoded_tx: flume::Sender<EncodedBatch<T>>,
+    completed_tx: flume::Sender<Completed<T>>,
+) {
+    while let Ok(mut batch) = batch_rx.recv_async().await {
+        let start = Instant::now();
+        let engine = worker.engine.clone();
+        let tokenized = std::mem::take(&mut batch.tokenized);
+        let encoded = run_blocking(move || engine.encode(tokenized)).await;
+        worker.record_stage("encode", start);
+
+        match encoded {
+            Ok(encoded) => {
+                if encoded_tx.send_async((batch, encoded)).await.is_err() {
+                    break;
+                }
+            }
+            Err(err) => {
+                let _ = completed_tx.send(Completed {
+                    worker: worker.index,
+                    positions: batch.positions,
+                    outputs: Err(err),
+                });
+            }
+        }
+    }
+}
+
+/// Second stage of the pipeline: runs the model on the worker's device.
+async fn forward_stage<T: BatchedEngine + 'static>(
+    worker: Worker<T>,
+    encoded_rx: flume::Receiver<EncodedBatch<T>>,
+    forwarded_tx: flume::Sender<ForwardedBatch<T>>,
+    completed_tx: flume::Sender<Completed<T>>,
+) {
+    while let Ok((batch, encoded)) = encoded_rx.recv_async().await {
+        let start = Instant::now();
+        let engine = worker.engine.clone();
+        let forwarded = run_blocking(move || {
+            let forwarded = engine.forward(&encoded)?;
+            Ok((encoded, forwarded))
+        })
+        .await;
+        worker.record_stage("forward", start);
+
+        match forwarded {
+            Ok((encoded, forwarded)) => {
+                if forwarded_tx
+                    .send_async((batch, encoded, forwarded))
+                    .await
+                    .is_err()
                 {
-                    let _ = response_tx.send(response_result);
+                    break;
                 }
             }
             Err(err) => {
-                tracing::error!("Batch processing failed: {}", err);
-                // Send error to all pending requests
-                for response_tx in response_channels {
-                    let _ =
-                        response_tx.send(Err(anyhow::anyhow!("Batch processing failed: {}", err)));
+                let _ = completed_tx.send(Completed {
+                    worker: worker.index,
+                    positions: batch.positions,
+                    outputs: Err(err),
+                });
+            }
+        }
+    }
+}
+
+/// Last stage of the pipeline: turns model outputs into per-input results
+/// and hands them back to the scheduler.
+async fn postprocess_stage<T: BatchedEngine + 'static>(
+    worker: Worker<T>,
+    forwarded_rx: flume::Receiver<ForwardedBatch<T>>,
+    completed_tx: flume::Sender<Completed<T>>,
+) {
+    while let Ok((batch, encoded, forwarded)) = forwarded_rx.recv_async().await {
+        let start = Instant::now();
+        let engine = worker.engine.clone();
+        let items = batch.items.clone();
+        let outputs = run_blocking(move || engine.postprocess(&items, encoded, forwarded)).await;
+        worker.record_stage("postprocess", start);
+        counter!(
+            "batches_processed_total",
+            "model" => worker.model.clone(),
+            "worker" => worker.index.to_string()
+        )
+        .increment(1);
+
+        let completed = Completed {
+            worker: worker.index,
+            positions: batch.positions,
+            outputs,
+        };
+        if completed_tx.send(completed).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A request whose inputs are given by their length in tokens.
+    struct StubRequest {
+        lengths: Vec<usize>,
+    }
+
+    impl BatchRequest for StubRequest {
+        fn input_len(&self) -> usize {
+            self.lengths.len()
+        }
+
+        fn timeout_ms(&self) -> Option<u64> {
+            None
+        }
+    }
+
+    /// An engine whose logits are the length of each input, so that outputs
+    /// can be matched back to their input. Inputs of length 0 cannot be
+    /// tokenized, and batches holding an input of `FAILING_LENGTH` fail on the
+    /// device.
+    struct StubEngine;
+
+    const FAILING_LENGTH: usize = 999;
+
+    impl BatchedEngine for StubEngine {
+        type Request = StubRequest;
+        type Tokenized = usize;
+        type Encoded = Vec<usize>;
+        type Forwarded = Vec<Vec<f32>>;
+        type Output = Vec<f32>;
+        type Response = Vec<Vec<f32>>;
+
+        fn tokenize(&self, request: &StubRequest, index: usize) -> Result<usize> {
+            match request.lengths[index] {
+                0 => Err(EngineError::InvalidInput {
+                    index,
+                    message: "empty input".to_string(),
                 }
+                .into()),
+                length => Ok(length),
+            }
+        }
+
+        fn sequence_lengths(&self, tokenized: &usize) -> Vec<usize> {
+            vec![*tokenized]
+        }
+
+        fn encode(&self, tokenized: Vec<usize>) -> Result<Vec<usize>> {
+            Ok(tokenized)
+        }
+
+        fn forward(&self, encoded: &Vec<usize>) -> Result<Vec<Vec<f32>>> {
+            if encoded.contains(&FAILING_LENGTH) {
+                anyhow::bail!("device error");
             }
+            Ok(encoded.iter().map(|&len| vec![len as f32, 1.0]).collect())
+        }
+
+        fn postprocess(
+            &self,
+            _items: &[WorkItem<StubRequest>],
+            _encoded: Vec<usize>,
+            forwarded: Vec<Vec<f32>>,
+        ) -> Result<Vec<Vec<f32>>> {
+            Ok(forwarded)
+        }
+
+        fn respond(&self, _request: &StubRequest, outputs: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
+            outputs
         }
+    }
+
+    type ResponseReceiver = oneshot::Receiver<Result<Vec<Vec<f32>>>>;
+
+    fn batch_config(batch_size: usize, length_buckets: Vec<usize>) -> BatchConfig {
+        BatchConfig {
+            batch_size,
+            max_batch_tokens: None,
+            length_buckets,
+            tick_duration: Duration::from_millis(10),
+            pipeline_depth: 1,
+            dispatch: Dispatch::LeastLoaded,
+            max_queue_size: None,
+            default_timeout: None,
+        }
+    }
+
+    fn processor(config: BatchConfig) -> BatchProcessor<StubEngine> {
+        BatchedEngineWrapper::new(config, "stub".to_string(), vec![StubEngine]).1
+    }
+
+    /// Front end of a processor running over `workers` stub workers.
+    fn spawn_engine(config: BatchConfig, workers: usize) -> BatchedEngineWrapper<StubEngine> {
+        let workers = (0..workers).map(|_| StubEngine).collect();
+        let (engine, processor) = BatchedEngineWrapper::new(config, "stub".to_string(), workers);
+        tokio::spawn(processor.run_forever());
+        engine
+    }
 
-        let processing_time = batch_start.elapsed();
-        tracing::info!(
-            processing_time_ms = processing_time.as_millis(),
-            "Batch processed"
+    /// Logits the stub engine returns for inputs of `lengths`.
+    fn logits(lengths: &[usize]) -> Vec<Vec<f32>> {
+        lengths.iter().map(|&len| vec![len as f32, 1.0]).collect()
+    }
+
+    fn is_engine_error(err: &anyhow::Error, expected: fn(&EngineError) -> bool) -> bool {
+        err.downcast_ref::<EngineError>().is_some_and(expected)
+    }
+
+    /// Queues a request straight into `processor`, as the front end would.
+    fn enqueue(processor: &mut BatchProcessor<StubEngine>, lengths: &[usize]) -> ResponseReceiver {
+        let (response_tx, response_rx) = oneshot::channel();
+        let queued_inputs = Arc::new(AtomicUsize::new(0));
+        processor.enqueue(QueuedRequest {
+            request: StubRequest {
+                lengths: lengths.to_vec(),
+            },
+            tokenized: lengths.to_vec(),
+            response_tx,
+            deadline: None,
+            slot: QueueSlot::acquire(&queued_inputs, lengths.len(), None).unwrap(),
+        });
+        response_rx
+    }
+
+    /// A dispatcher over `workers` workers that each hold one batch, with the
+    /// receiving end of each worker's channel.
+    fn dispatcher(
+        workers: usize,
+        policy: Dispatch,
+    ) -> (
+        Dispatcher<StubEngine>,
+        Vec<flume::Receiver<Batch<StubEngine>>>,
+    ) {
+        let (senders, receivers) = (0..workers).map(|_| flume::bounded(1)).unzip();
+        let dispatcher = Dispatcher {
+            model: "stub".to_string(),
+            policy,
+            senders,
+            in_flight: vec![0; workers],
+            next: 0,
+        };
+        (dispatcher, receivers)
+    }
+
+    #[test]
+    fn long_inputs_are_served_while_short_ones_keep_arriving() {
+        let mut processor = processor(batch_config(2, vec![8]));
+        let (mut dispatcher, receivers) = dispatcher(1, Dispatch::LeastLoaded);
+        let mut responses = Vec::new();
+
+        // Keep the only worker busy with short inputs
+        responses.push(enqueue(&mut processor, &[1, 1]));
+        processor.dispatch_ready(&mut dispatcher);
+        std::thread::sleep(Duration::from_millis(2));
+        responses.push(enqueue(&mut processor, &[100, 100]));
+
+        let mut served = Vec::new();
+        for _ in 0..3 {
+            std::thread::sleep(Duration::from_millis(2));
+            responses.push(enqueue(&mut processor, &[1, 1]));
+
+            let batch = receivers[0].try_recv().expect("worker has a batch");
+            served.push(batch.tokenized);
+            dispatcher.completed(0);
+            processor.dispatch_ready(&mut dispatcher);
+        }
+
+        assert_eq!(served, [vec![1, 1], vec![100, 100], vec![1, 1]]);
+    }
+
+    #[tokio::test]
+    async fn reassembles_a_request_spread_over_several_batches() {
+        let engine = spawn_engine(batch_config(2, Vec::new()), 2);
+
+        let lengths = [3, 1, 4, 1, 5];
+        let response = engine
+            .submit(StubRequest {
+                lengths: lengths.to_vec(),
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(response, logits(&lengths));
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_get_their_own_outputs() {
+        let engine = Arc::new(spawn_engine(batch_config(4, vec![4]), 2));
+
+        let requests = (1..=12).map(|count| {
+            let engine = engine.clone();
+            let lengths: Vec<usize> = (0..count).map(|index| 1 + (count + index) % 7).collect();
+            tokio::spawn(async move {
+                let response = engine
+                    .submit(StubRequest {
+                        lengths: lengths.clone(),
+                    })
+                    .await
+                    .unwrap();
+                (lengths, response)
+            })
+        });
+
+        for request in requests.collect::<Vec<_>>() {
+            let (lengths, response) = request.await.unwrap();
+            assert_eq!(response, logits(&lengths));
+        }
+    }
+
+    #[tokio::test]
+    async fn device_errors_fail_only_the_requests_in_the_batch() {
+        let engine = Arc::new(spawn_engine(batch_config(1, Vec::new()), 1));
+
+        let (failed, served) = tokio::join!(
+            engine.submit(StubRequest {
+                lengths: vec![1, FAILING_LENGTH],
+            }),
+            engine.submit(StubRequest { lengths: vec![2] }),
         );
+
+        let err = failed.unwrap_err();
+        assert!(err.to_string().contains("device error"), "{err}");
+        assert_eq!(served.unwrap(), logits(&[2]));
+    }
+
+    #[tokio::test]
+    async fn inputs_that_cannot_be_tokenized_are_rejected_before_queueing() {
+        let engine = spawn_engine(batch_config(2, Vec::new()), 1);
+
+        let err = engine
+            .submit(StubRequest {
+                lengths: vec![1, 0],
+            })
+            .await
+            .unwrap_err();
+
+        assert!(is_engine_error(&err, |err| matches!(
+            err,
+            EngineError::InvalidInput { index: 1, .. }
+        )));
+        assert_eq!(engine.queued_inputs.load(Ordering::Acquire), 0);
+    }
+
+    #[test]
+    fn completes_a_request_once_all_its_inputs_are_done() {
+        let mut processor = processor(batch_config(2, Vec::new()));
+        let mut response = enqueue(&mut processor, &[1, 2, 3]);
+        let first = processor.next_batch(0).unwrap();
+        let second = processor.next_batch(0).unwrap();
+
+        // The second batch completes first
+        processor.complete_batch(Completed {
+            worker: 0,
+            positions: second.positions,
+            outputs: Ok(logits(&[3])),
+        });
+        assert!(response.try_recv().is_err());
+
+        processor.complete_batch(Completed {
+            worker: 0,
+            positions: first.positions,
+            outputs: Ok(logits(&[1, 2])),
+        });
+        assert_eq!(response.try_recv().unwrap().unwrap(), logits(&[1, 2, 3]));
+        assert!(processor.jobs.is_empty());
+    }
+
+    #[test]
+    fn failed_batch_drops_the_queued_inputs_of_its_requests() {
+        let mut processor = processor(batch_config(2, Vec::new()));
+        let mut failed = enqueue(&mut processor, &[1, 2, 3]);
+        let mut other = enqueue(&mut processor, &[4]);
+        let batch = processor.next_batch(0).unwrap();
+
+        processor.complete_batch(Completed {
+            worker: 0,
+            positions: batch.positions,
+            outputs: Err(anyhow::anyhow!("device error")),
+        });
+
+        assert!(failed.try_recv().unwrap().is_err());
+        assert!(other.try_recv().is_err());
+        // Only the other request's input is left
+        assert_eq!(processor.queue_len(), 1);
+        assert_eq!(processor.jobs.len(), 1);
+    }
+
+    #[test]
+    fn queue_slots_track_queued_inputs() {
+        let queued_inputs = Arc::new(AtomicUsize::new(0));
+        let queued = || queued_inputs.load(Ordering::Acquire);
+
+        let mut slot = QueueSlot::acquire(&queued_inputs, 3, Some(5)).unwrap();
+        assert_eq!(queued(), 3);
+
+        let err = QueueSlot::acquire(&queued_inputs, 3, Some(5)).unwrap_err();
+        assert!(matches!(err, EngineError::Overloaded));
+        assert_eq!(queued(), 3);
+
+        let err = QueueSlot::acquire(&queued_inputs, 6, Some(5)).unwrap_err();
+        assert!(matches!(
+            err,
+            EngineError::TooManyInputs {
+                inputs: 6,
+                limit: 5
+            }
+        ));
+        assert_eq!(queued(), 3);
+
+        slot.release(2);
+        assert_eq!(queued(), 1);
+        slot.release(5);
+        assert_eq!(queued(), 0);
+        drop(slot);
+        assert_eq!(queued(), 0);
+
+        let slot = QueueSlot::acquire(&queued_inputs, 4, None).unwrap();
+        drop(slot);
+        assert_eq!(queued(), 0);
+    }
+
+    #[test]
+    fn queue_slots_are_released_as_inputs_are_batched_or_dropped() {
+        let mut processor = processor(batch_config(2, Vec::new()));
+        let queued_inputs = Arc::new(AtomicUsize::new(0));
+        let (response_tx, response_rx) = oneshot::channel();
+        processor.enqueue(QueuedRequest {
+            request: StubRequest {
+                lengths: vec![1, 2, 3],
+            },
+            tokenized: vec![1, 2, 3],
+            response_tx,
+            deadline: None,
+            slot: QueueSlot::acquire(&queued_inputs, 3, None).unwrap(),
+        });
+        assert_eq!(queued_inputs.load(Ordering::Acquire), 3);
+
+        processor.next_batch(0).unwrap();
+        assert_eq!(queued_inputs.load(Ordering::Acquire), 1);
+
+        // The caller goes away before its last input is batched
+        drop(response_rx);
+        processor.purge_stale();
+        assert_eq!(queued_inputs.load(Ordering::Acquire), 0);
+        assert_eq!(processor.queue_len(), 0);
+    }
+
+    #[test]
+    fn round_robin_takes_turns_among_workers_with_room() {
+        let (mut dispatcher, receivers) = dispatcher(3, Dispatch::RoundRobin);
+        let mut processor = processor(batch_config(1, Vec::new()));
+        let _responses: Vec<_> = (1..=4)
+            .map(|length| enqueue(&mut processor, &[length]))
+            .collect();
+
+        for expected in 0..3 {
+            let worker = dispatcher.pick().unwrap();
+            assert_eq!(worker, expected);
+            let batch = processor.next_batch(0).unwrap();
+            assert!(dispatcher.send(worker, batch).is_ok());
+        }
+        // Every worker holds a batch
+        assert_eq!(dispatcher.pick(), None);
+
+        receivers[1].try_recv().unwrap();
+        dispatcher.completed(1);
+        assert_eq!(dispatcher.pick(), Some(1));
+    }
+
+    #[test]
+    fn least_loaded_picks_the_worker_with_fewest_batches() {
+        let (mut dispatcher, _receivers) = dispatcher(3, Dispatch::LeastLoaded);
+
+        dispatcher.in_flight = vec![2, 0, 1];
+        assert_eq!(dispatcher.pick(), Some(1));
+
+        // Ties go to the next worker in turn
+        dispatcher.in_flight = vec![1, 1, 1];
+        dispatcher.next = 2;
+        assert_eq!(dispatcher.pick(), Some(2));
+        dispatcher.next = 0;
+        assert_eq!(dispatcher.pick(), Some(0));
+    }
+
+    #[test]
+    fn batches_for_a_stopped_worker_fail_their_requests() {
+        let (mut dispatcher, receivers) = dispatcher(1, Dispatch::LeastLoaded);
+        drop(receivers);
+        let mut processor = processor(batch_config(2, Vec::new()));
+        let mut response = enqueue(&mut processor, &[1, 2]);
+
+        processor.dispatch_ready(&mut dispatcher);
+
+        let err = response.try_recv().unwrap().unwrap_err();
+        assert!(is_engine_error(&err, |err| matches!(
+            err,
+            EngineError::Unavailable
+        )));
+        assert_eq!(dispatcher.in_flight, [0]);
+        assert!(processor.jobs.is_empty());
     }
 }
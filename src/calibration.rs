@@ -0,0 +1,421 @@
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::config::{CalibrateArgs, Config, ProblemType, Task};
+use crate::deberta_engine::{DebertaBatchedEngine, DebertaConfig};
+use crate::engine::{BatchedEngine, WorkItem};
+use crate::registry::deberta_config;
+use crate::types::{ClassificationOptions, ClassificationRequest, TextInput};
+
+/// Calibration parameters applied to a model's logits before they are turned
+/// into scores: `logits / temperature * scale + bias`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Calibration {
+    pub temperature: f64,
+    /// Per-class scale, for vector scaling
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale: Option<Vec<f64>>,
+    /// Per-class bias, for vector scaling
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bias: Option<Vec<f64>>,
+}
+
+impl Calibration {
+    /// Reads a calibration file written by the `calibrate` command, checking
+    /// it matches a model with `num_classes` outputs.
+    pub fn load(path: &Path, num_classes: usize) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read calibration file {}", path.display()))?;
+        let calibration: Self = serde_json::from_str(&contents)
+            .with_context(|| format!("Invalid calibration file {}", path.display()))?;
+
+        if !calibration.temperature.is_finite() || calibration.temperature <= 0.0 {
+            bail!("Calibration temperature must be positive");
+        }
+        for (name, values) in [("scale", &calibration.scale), ("bias", &calibration.bias)] {
+            if let Some(values) = values
+                && values.len() != num_classes
+            {
+                bail!(
+                    "Calibration {name} has {} values but the model has {num_classes} classes",
+                    values.len()
+                );
+            }
+        }
+        Ok(calibration)
+    }
+
+    /// Calibrated logits of one input.
+    pub fn apply(&self, logits: &[f32]) -> Vec<f32> {
+        logits
+            .iter()
+            .enumerate()
+            .map(|(class, &logit)| {
+                let scale = self.scale.as_ref().map_or(1.0, |scale| scale[class]);
+                let bias = self.bias.as_ref().map_or(0.0, |bias| bias[class]);
+                (logit as f64 / self.temperature * scale + bias) as f32
+            })
+            .collect()
+    }
+
+    /// Fits the temperature minimizing the negative log-likelihood of `labels`
+    /// under the softmax of `logits`, then optionally a per-class scale and
+    /// bias on top of it.
+    pub fn fit(logits: &[Vec<f32>], labels: &[usize], vector_scaling: bool) -> Self {
+        let temperature = fit_temperature(logits, labels);
+        let mut calibration = Self {
+            temperature,
+            scale: None,
+            bias: None,
+        };
+
+        if vector_scaling {
+            let scaled: Vec<Vec<f64>> = logits
+                .iter()
+                .map(|row| row.iter().map(|&x| x as f64 / temperature).collect())
+                .collect();
+            let (scale, bias) = fit_vector_scaling(&scaled, labels);
+            calibration.scale = Some(scale);
+            calibration.bias = Some(bias);
+        }
+
+        calibration
+    }
+}
+
+/// Mean negative log-likelihood of `labels` under the softmax of `logits`.
+pub fn negative_log_likelihood(logits: &[Vec<f64>], labels: &[usize]) -> f64 {
+    let total: f64 = logits
+        .iter()
+        .zip(labels)
+        .map(|(row, &label)| log_sum_exp(row) - row[label])
+        .sum();
+    total / logits.len() as f64
+}
+
+fn log_sum_exp(row: &[f64]) -> f64 {
+    let max = row.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    max + row.iter().map(|x| (x - max).exp()).sum::<f64>().ln()
+}
+
+/// Golden-section search over the log temperature, on which the NLL is
+/// unimodal.
+fn fit_temperature(logits: &[Vec<f32>], labels: &[usize]) -> f64 {
+    let nll = |log_temperature: f64| {
+        let temperature = log_temperature.exp();
+        let scaled: Vec<Vec<f64>> = logits
+            .iter()
+            .map(|row| row.iter().map(|&x| x as f64 / temperature).collect())
+            .collect();
+        negative_log_likelihood(&scaled, labels)
+    };
+
+    let ratio = (5f64.sqrt() - 1.0) / 2.0;
+    let (mut low, mut high) = (0.01f64.ln(), 100f64.ln());
+    while high - low > 1e-6 {
+        let left = high - ratio * (high - low);
+        let right = low + ratio * (high - low);
+        if nll(left) < nll(right) {
+            high = right;
+        } else {
+            low = left;
+        }
+    }
+    ((low + high) / 2.0).exp()
+}
+
+/// Gradient descent on the per-class scale and bias; the NLL is convex in both.
+/// Steps that would increase the NLL are retried with half the learning rate,
+/// and the descent stops once the NLL no longer improves.
+fn fit_vector_scaling(logits: &[Vec<f64>], labels: &[usize]) -> (Vec<f64>, Vec<f64>) {
+    const ITERATIONS: usize = 2000;
+    const MIN_LEARNING_RATE: f64 = 1e-8;
+    const TOLERANCE: f64 = 1e-10;
+
+    let num_classes = logits.first().map_or(0, Vec::len);
+    let mut scale = vec![1.0; num_classes];
+    let mut bias = vec![0.0; num_classes];
+    let mut learning_rate = 0.1;
+    let mut nll = negative_log_likelihood(&vector_scaled(logits, &scale, &bias), labels);
+    let count = logits.len() as f64;
+
+    for _ in 0..ITERATIONS {
+        let mut scale_grad = vec![0.0; num_classes];
+        let mut bias_grad = vec![0.0; num_classes];
+
+        for (row, &label) in logits.iter().zip(labels) {
+            let calibrated: Vec<f64> = (0..num_classes)
+                .map(|class| row[class] * scale[class] + bias[class])
+                .collect();
+            let normalizer = log_sum_exp(&calibrated);
+            for class in 0..num_classes {
+                let error =
+                    (calibrated[class] - normalizer).exp() - if class == label { 1.0 } else { 0.0 };
+                scale_grad[class] += error * row[class] / count;
+                bias_grad[class] += error / count;
+            }
+        }
+
+        let next_scale: Vec<f64> = (0..num_classes)
+            .map(|class| scale[class] - learning_rate * scale_grad[class])
+            .collect();
+        let next_bias: Vec<f64> = (0..num_classes)
+            .map(|class| bias[class] - learning_rate * bias_grad[class])
+            .collect();
+        let next_nll =
+            negative_log_likelihood(&vector_scaled(logits, &next_scale, &next_bias), labels);
+
+        // Overshot (or hit a non-finite value): retry with a smaller step
+        if next_nll.is_nan() || next_nll > nll {
+            learning_rate /= 2.0;
+            if learning_rate < MIN_LEARNING_RATE {
+                break;
+            }
+            continue;
+        }
+
+        let improvement = nll - next_nll;
+        scale = next_scale;
+        bias = next_bias;
+        nll = next_nll;
+        if improvement < TOLERANCE {
+            break;
+        }
+    }
+
+    (scale, bias)
+}
+
+fn vector_scaled(logits: &[Vec<f64>], scale: &[f64], bias: &[f64]) -> Vec<Vec<f64>> {
+    logits
+        .iter()
+        .map(|row| {
+            row.iter()
+                .zip(scale.iter().zip(bias))
+                .map(|(x, (scale, bias))| x * scale + bias)
+                .collect()
+        })
+        .collect()
+}
+
+/// One line of the validation set read by the `calibrate` command.
+#[derive(Debug, Deserialize)]
+struct LabeledExample {
+    #[serde(flatten)]
+    input: TextInput,
+    label: LabelRef,
+}
+
+/// A label given either by name or by class id.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum LabelRef {
+    Id(usize),
+    Name(String),
+}
+
+/// Runs the validation set in `args.data` through the model and writes the
+/// fitted calibration to `args.output`.
+pub async fn run(config: &Config, args: &CalibrateArgs) -> Result<()> {
+    let models = config.model_configs()?;
+    let model = match &args.model {
+        Some(name) => models
+            .into_iter()
+            .find(|model| &model.name == name)
+            .with_context(|| format!("No model named `{name}` is configured"))?,
+        None if models.len() == 1 => models.into_iter().next().expect("one model"),
+        None => bail!("Several models are configured, pick one with --model"),
+    };
+
+    if model.task != Task::TextClassification {
+        bail!(
+            "Only text classification models can be calibrated, `{}` is a token classification model",
+            model.name
+        );
+    }
+
+    // Fit on the uncalibrated logits
+    let deberta_config = DebertaConfig {
+        calibration: None,
+        ..deberta_config(config, &model)?
+    };
+    let engine = DebertaBatchedEngine::new(deberta_config).await?;
+    if engine.problem_type() != ProblemType::SingleLabelClassification {
+        bail!(
+            "Only single-label classification models can be calibrated, `{}` has problem type {:?}",
+            model.name,
+            engine.problem_type()
+        );
+    }
+    let label_ids: HashMap<&str, usize> = engine
+        .id2label()
+        .iter()
+        .map(|(&id, label)| (label.as_str(), id as usize))
+        .collect();
+    let num_classes = engine.id2label().len();
+
+    let contents = std::fs::read_to_string(&args.data)
+        .with_context(|| format!("Failed to read {}", args.data.display()))?;
+    let mut inputs = Vec::new();
+    let mut labels = Vec::new();
+    for (line_number, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let example: LabeledExample = serde_json::from_str(line)
+            .with_context(|| format!("Invalid example on line {}", line_number + 1))?;
+        let label = match &example.label {
+            LabelRef::Id(id) if *id < num_classes => *id,
+            LabelRef::Name(name) if label_ids.contains_key(name.as_str()) => {
+                label_ids[name.as_str()]
+            }
+            label => bail!("Unknown label {label:?} on line {}", line_number + 1),
+        };
+        inputs.push(example.input);
+        labels.push(label);
+    }
+    if inputs.is_empty() {
+        bail!("No examples found in {}", args.data.display());
+    }
+    tracing::info!(examples = inputs.len(), model = %model.name, "Collecting logits");
+
+    let mut logits = Vec::with_capacity(inputs.len());
+    for chunk in inputs.chunks(config.batch_size.max(1)) {
+        let request = Arc::new(ClassificationRequest {
+            model: model.name.clone(),
+            input: chunk.to_vec(),
+            timeout_ms: None,
+            options: ClassificationOptions {
+                return_logits: true,
+                ..Default::default()
+            },
+        });
+        let items: Vec<_> = (0..chunk.len())
+            .map(|index| WorkItem {
+                request: request.clone(),
+                index,
+            })
+            .collect();
+        logits.extend(engine.classify_batch(&items)?.into_iter().map(|output| {
+            output
+                .data
+                .logits
+                .unwrap_or_default()
+                .into_iter()
+                .map(|x| x as f32)
+                .collect::<Vec<f32>>()
+        }));
+    }
+
+    let calibration = Calibration::fit(&logits, &labels, args.vector_scaling);
+
+    let nll = |calibration: Option<&Calibration>| {
+        let rows: Vec<Vec<f64>> = logits
+            .iter()
+            .map(|row| {
+                let row = calibration.map_or_else(|| row.clone(), |c| c.apply(row));
+                row.into_iter().map(f64::from).collect()
+            })
+            .collect();
+        negative_log_likelihood(&rows, &labels)
+    };
+    tracing::info!(
+        temperature = calibration.temperature,
+        nll_before = nll(None),
+        nll_after = nll(Some(&calibration)),
+        "Fitted calibration"
+    );
+
+    std::fs::write(&args.output, serde_json::to_string_pretty(&calibration)?)
+        .with_context(|| format!("Failed to write {}", args.output.display()))?;
+    tracing::info!(path = %args.output.display(), "Calibration written");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two-class logits with a margin of 5 towards class 0, whose label is
+    /// right 7 times out of 10.
+    fn overconfident_set() -> (Vec<Vec<f32>>, Vec<usize>) {
+        let logits = vec![vec![5.0, 0.0]; 10];
+        let labels = [0, 0, 0, 0, 0, 0, 0, 1, 1, 1].to_vec();
+        (logits, labels)
+    }
+
+    fn to_f64(logits: &[Vec<f32>]) -> Vec<Vec<f64>> {
+        logits
+            .iter()
+            .map(|row| row.iter().map(|&x| x as f64).collect())
+            .collect()
+    }
+
+    #[test]
+    fn fit_temperature_softens_overconfident_logits() {
+        let (logits, labels) = overconfident_set();
+
+        let temperature = fit_temperature(&logits, &labels);
+
+        // The NLL is minimal where sigmoid(5 / T) = 0.7
+        let expected = 5.0 / (0.7f64 / 0.3).ln();
+        assert!(temperature > 1.0);
+        assert!(
+            (temperature - expected).abs() < 1e-3,
+            "temperature {temperature}, expected {expected}"
+        );
+    }
+
+    #[test]
+    fn fit_vector_scaling_does_not_increase_nll() {
+        let logits = vec![
+            vec![2.0, 0.5, -1.0],
+            vec![1.5, 1.0, 0.0],
+            vec![0.0, 2.5, 0.5],
+            vec![-0.5, 0.0, 1.5],
+            vec![3.0, -1.0, 0.0],
+            vec![0.5, 0.5, 0.5],
+        ];
+        let labels = vec![0, 1, 1, 2, 2, 0];
+        let before = negative_log_likelihood(&logits, &labels);
+
+        let (scale, bias) = fit_vector_scaling(&logits, &labels);
+        let after = negative_log_likelihood(&vector_scaled(&logits, &scale, &bias), &labels);
+
+        assert!(after <= before, "NLL went from {before} to {after}");
+    }
+
+    #[test]
+    fn fit_vector_scaling_does_not_diverge_on_large_logits() {
+        let (logits, labels) = overconfident_set();
+        let logits: Vec<Vec<f64>> = to_f64(&logits)
+            .into_iter()
+            .map(|row| row.into_iter().map(|x| x * 200.0).collect())
+            .collect();
+        let before = negative_log_likelihood(&logits, &labels);
+
+        let (scale, bias) = fit_vector_scaling(&logits, &labels);
+        let after = negative_log_likelihood(&vector_scaled(&logits, &scale, &bias), &labels);
+
+        assert!(scale.iter().chain(&bias).all(|x| x.is_finite()));
+        assert!(
+            after.is_finite() && after <= before,
+            "NLL went from {before} to {after}"
+        );
+    }
+
+    #[test]
+    fn fit_improves_nll_of_overconfident_logits() {
+        let (logits, labels) = overconfident_set();
+        let before = negative_log_likelihood(&to_f64(&logits), &labels);
+
+        let calibration = Calibration::fit(&logits, &labels, true);
+        let calibrated: Vec<Vec<f32>> = logits.iter().map(|row| calibration.apply(row)).collect();
+        let after = negative_log_likelihood(&to_f64(&calibrated), &labels);
+
+        assert!(after < before, "NLL went from {before} to {after}");
+    }
+}
@@ -1,19 +1,69 @@
-use clap::Parser;
-use std::collections::HashMap;
+use anyhow::{Context, Result, bail};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::time::Duration;
 
 #[derive(Debug, Clone, Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Config {
     /// Batch size for processing requests
-    #[arg(long, env = "BATCH_SIZE", default_value = "8")]
+    #[arg(
+        long,
+        env = "BATCH_SIZE",
+        default_value = "8",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
     pub batch_size: usize,
 
+    /// Maximum padded tokens per batch (sequences x longest sequence), unlimited if unset
+    #[arg(long, env = "MAX_BATCH_TOKENS")]
+    pub max_batch_tokens: Option<usize>,
+
+    /// Token-length bucket boundaries, e.g. "64,128,256"; batches only mix
+    /// requests from the same bucket
+    #[arg(long, env = "LENGTH_BUCKETS", value_delimiter = ',')]
+    pub length_buckets: Vec<usize>,
+
     /// Tick duration in milliseconds for batch processing
     #[arg(long, env = "TICK_DURATION_MS", default_value = "100")]
     pub tick_duration_ms: u64,
 
+    /// Number of batches that may wait between each stage of the
+    /// encode, device and post-process pipeline
+    #[arg(long, env = "PIPELINE_DEPTH", default_value = "2")]
+    pub pipeline_depth: usize,
+
+    /// Number of batch workers per model, each with its own copy of the
+    /// model; per listed device with `--device`, otherwise worker N runs on
+    /// GPU N, wrapping around the GPUs found
+    #[arg(long, env = "WORKERS", default_value = "1")]
+    pub workers: usize,
+
+    /// Maximum number of inputs waiting to be batched before new requests are
+    /// rejected, unlimited if unset
+    #[arg(long, env = "MAX_QUEUE_SIZE")]
+    pub max_queue_size: Option<usize>,
+
+    /// Default time limit in milliseconds for a request, unlimited if unset
+    #[arg(long, env = "REQUEST_TIMEOUT_MS")]
+    pub request_timeout_ms: Option<u64>,
+
+    /// Maximum number of inputs in a single request
+    #[arg(long, env = "MAX_INPUTS_PER_REQUEST", default_value = "2048")]
+    pub max_inputs_per_request: usize,
+
+    /// Maximum number of characters in a single input, unlimited if unset
+    #[arg(long, env = "MAX_INPUT_CHARS")]
+    pub max_input_chars: Option<usize>,
+
+    /// Accept empty strings as inputs instead of rejecting the request
+    #[arg(long, env = "ALLOW_EMPTY_INPUTS")]
+    pub allow_empty_inputs: bool,
+
     /// Server host to bind to
     #[arg(long, env = "HOST", default_value = "127.0.0.1")]
     pub host: String,
@@ -42,6 +92,15 @@ pub struct Config {
     #[arg(long, env = "CPU_ONLY")]
     pub cpu_only: bool,
 
+    /// Devices to place model replicas on, e.g. "cpu", "cuda:1" or "cuda:0,1";
+    /// each device gets `--workers` replicas. Picked automatically if unset
+    #[arg(long, env = "DEVICE")]
+    pub device: Option<DeviceList>,
+
+    /// How batches are spread across a model's workers
+    #[arg(long, env = "DISPATCH", value_enum, default_value = "least-loaded")]
+    pub dispatch: Dispatch,
+
     /// Maximum sequence length allowed
     #[arg(long, env = "MAX_SEQUENCE_LENGTH", default_value = "512")]
     pub max_sequence_length: usize,
@@ -49,19 +108,271 @@ pub struct Config {
     /// Labels mapping in format "0=No Claim,1=Claim"
     #[arg(long, env = "ID2LABEL")]
     pub id2label: Option<String>,
+
+    /// Kind of head the model has, which decides the endpoints it serves
+    #[arg(long, env = "TASK", value_enum, default_value_t = Task::TextClassification)]
+    pub task: Task,
+
+    /// How model outputs are interpreted; read from the model's config.json if unset
+    #[arg(long, env = "PROBLEM_TYPE", value_enum)]
+    pub problem_type: Option<ProblemType>,
+
+    /// Probability above which a label is returned for multi-label models
+    #[arg(long, env = "MULTI_LABEL_THRESHOLD", default_value = "0.5")]
+    pub multi_label_threshold: f64,
+
+    /// Per-label decision thresholds in format "Claim=0.7,No Claim=0.5"
+    #[arg(long, env = "LABEL_THRESHOLDS")]
+    pub label_thresholds: Option<String>,
+
+    /// Minimum probability of the predicted label; less confident inputs get the abstain label
+    #[arg(long, env = "MIN_CONFIDENCE")]
+    pub min_confidence: Option<f64>,
+
+    /// Label returned for inputs below their decision threshold
+    #[arg(long, env = "ABSTAIN_LABEL", default_value = "UNCERTAIN")]
+    pub abstain_label: String,
+
+    /// Name the model given by --model-id/--model-path is served under
+    #[arg(long, env = "SERVED_MODEL_NAME")]
+    pub served_model_name: Option<String>,
+
+    /// Path to a JSON file listing several models to serve
+    #[arg(long, env = "MODELS_CONFIG", conflicts_with_all = ["model_id", "model_path"])]
+    pub models_config: Option<PathBuf>,
+
+    /// Calibration file written by the `calibrate` command, applied to the logits
+    #[arg(long, env = "CALIBRATION_FILE")]
+    pub calibration: Option<PathBuf>,
+
+    /// Overlap in tokens between the windows of chunked inputs; an eighth of the
+    /// maximum sequence length if unset
+    #[arg(long, env = "CHUNK_STRIDE")]
+    pub chunk_stride: Option<usize>,
+
+    /// Maximum number of windows a chunked input may be split into; longer
+    /// inputs are rejected
+    #[arg(
+        long,
+        env = "MAX_CHUNKS",
+        default_value = "32",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub max_chunks: usize,
+
+    /// Leave special tokens such as [CLS] and [SEP] out of token usage
+    #[arg(long, env = "EXCLUDE_SPECIAL_TOKENS")]
+    pub exclude_special_tokens: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Fit calibration parameters on a labeled validation set and write them to a file
+    Calibrate(CalibrateArgs),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct CalibrateArgs {
+    /// JSONL validation set, one `{"text", "text_pair", "label"}` object per line
+    #[arg(long)]
+    pub data: PathBuf,
+
+    /// Where to write the calibration file
+    #[arg(long)]
+    pub output: PathBuf,
+
+    /// Name of the model to calibrate, when several are configured
+    #[arg(long)]
+    pub model: Option<String>,
+
+    /// Also fit a per-class scale and bias on top of the temperature
+    #[arg(long)]
+    pub vector_scaling: bool,
+}
+
+/// A single model served by the registry, as listed in `--models-config`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModelConfig {
+    /// Name clients pass in the `model` field of a request
+    pub name: String,
+    pub model_id: Option<String>,
+    pub model_path: Option<PathBuf>,
+    #[serde(default = "default_revision")]
+    pub revision: String,
+    #[serde(default)]
+    pub use_pth: bool,
+    pub max_sequence_length: Option<usize>,
+    pub id2label: Option<HashMap<u32, String>>,
+    #[serde(default)]
+    pub task: Task,
+    pub problem_type: Option<ProblemType>,
+    pub multi_label_threshold: Option<f64>,
+    pub label_thresholds: Option<HashMap<String, f64>>,
+    pub min_confidence: Option<f64>,
+    pub abstain_label: Option<String>,
+    pub calibration: Option<PathBuf>,
+    pub chunk_stride: Option<usize>,
+    pub max_chunks: Option<usize>,
+    pub workers: Option<usize>,
+    pub device: Option<DeviceList>,
+}
+
+/// The head a model is loaded with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Task {
+    /// A label (or score) per input, served by `/classify` and `/zero-shot`
+    #[default]
+    TextClassification,
+    /// A label per token, served by `/token-classify`
+    TokenClassification,
+}
+
+/// How a sequence classification head is trained, matching the Hugging Face
+/// `problem_type` config field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemType {
+    /// One label per input: softmax over the logits
+    #[default]
+    SingleLabelClassification,
+    /// Any number of labels per input: sigmoid per logit
+    MultiLabelClassification,
+    /// A single real-valued output, returned as is
+    Regression,
+}
+
+/// A device a model replica can be placed on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceSpec {
+    Cpu,
+    Cuda(usize),
+    Metal(usize),
+}
+
+impl fmt::Display for DeviceSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cpu => write!(f, "cpu"),
+            Self::Cuda(ordinal) => write!(f, "cuda:{ordinal}"),
+            Self::Metal(ordinal) => write!(f, "metal:{ordinal}"),
+        }
+    }
+}
+
+/// A comma-separated list of devices such as "cuda:0,cuda:1", where bare
+/// ordinals reuse the previous device type: "cuda:0,1".
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct DeviceList(pub Vec<DeviceSpec>);
+
+impl FromStr for DeviceList {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let mut devices = Vec::new();
+        let mut kind = None;
+        for item in spec.split(',').map(str::trim) {
+            let (name, ordinal) = match item.split_once(':') {
+                Some((name, ordinal)) => (name, Some(ordinal)),
+                None if item.parse::<usize>().is_ok() => match kind {
+                    Some(name) => (name, Some(item)),
+                    None => bail!("Device ordinal `{item}` must follow a device type"),
+                },
+                None => (item, None),
+            };
+            let ordinal = ordinal
+                .map(|ordinal| {
+                    ordinal
+                        .parse()
+                        .with_context(|| format!("Invalid device ordinal `{ordinal}`"))
+                })
+                .transpose()?;
+            devices.push(match (name, ordinal) {
+                ("cpu", None) => DeviceSpec::Cpu,
+                ("cuda", ordinal) => DeviceSpec::Cuda(ordinal.unwrap_or(0)),
+                ("metal", ordinal) => DeviceSpec::Metal(ordinal.unwrap_or(0)),
+                _ => bail!("Unknown device `{item}`, expected cpu, cuda:N or metal:N"),
+            });
+            kind = Some(name);
+        }
+        Ok(Self(devices))
+    }
+}
+
+impl TryFrom<String> for DeviceList {
+    type Error = anyhow::Error;
+
+    fn try_from(spec: String) -> Result<Self> {
+        spec.parse()
+    }
+}
+
+/// How the batch processor picks the worker for the next batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Dispatch {
+    /// Each worker in turn
+    RoundRobin,
+    /// The worker with the fewest batches in flight
+    #[default]
+    LeastLoaded,
+}
+
+fn default_revision() -> String {
+    "main".to_string()
 }
 
 #[derive(Debug, Clone)]
 pub struct BatchConfig {
     pub batch_size: usize,
+    pub max_batch_tokens: Option<usize>,
+    /// Sorted upper bounds (inclusive) of each length bucket
+    pub length_buckets: Vec<usize>,
     pub tick_duration: Duration,
+    /// Capacity of the channels between pipeline stages
+    pub pipeline_depth: usize,
+    pub dispatch: Dispatch,
+    pub max_queue_size: Option<usize>,
+    /// Applied to requests that don't set `timeout_ms`
+    pub default_timeout: Option<Duration>,
 }
 
 impl From<&Config> for BatchConfig {
     fn from(config: &Config) -> Self {
+        let mut length_buckets = config.length_buckets.clone();
+        length_buckets.sort_unstable();
+        length_buckets.dedup();
+
         Self {
             batch_size: config.batch_size,
+            max_batch_tokens: config.max_batch_tokens,
+            length_buckets,
             tick_duration: Duration::from_millis(config.tick_duration_ms),
+            pipeline_depth: config.pipeline_depth,
+            dispatch: config.dispatch,
+            max_queue_size: config.max_queue_size,
+            default_timeout: config.request_timeout_ms.map(Duration::from_millis),
+        }
+    }
+}
+
+/// Limits checked on every request before it is queued.
+#[derive(Debug, Clone)]
+pub struct RequestLimits {
+    pub max_inputs: usize,
+    pub max_input_chars: Option<usize>,
+    pub allow_empty_inputs: bool,
+}
+
+impl From<&Config> for RequestLimits {
+    fn from(config: &Config) -> Self {
+        Self {
+            max_inputs: config.max_inputs_per_request,
+            max_input_chars: config.max_input_chars,
+            allow_empty_inputs: config.allow_empty_inputs,
         }
     }
 }
@@ -81,7 +392,187 @@ impl Config {
         })
     }
 
+    /// Parses `--label-thresholds`, failing on the first entry that isn't a
+    /// `LABEL=THRESHOLD` pair.
+    pub fn parse_label_thresholds(&self) -> Result<Option<HashMap<String, f64>>> {
+        let Some(thresholds) = &self.label_thresholds else {
+            return Ok(None);
+        };
+        thresholds
+            .split(',')
+            .map(str::trim)
+            .map(|pair| {
+                let (label, threshold) = pair.rsplit_once('=').with_context(|| {
+                    format!("Invalid label threshold `{pair}`, expected LABEL=THRESHOLD")
+                })?;
+                let threshold = threshold.trim().parse().with_context(|| {
+                    format!("Invalid threshold `{threshold}` for label `{label}`")
+                })?;
+                Ok((label.to_string(), threshold))
+            })
+            .collect::<Result<_>>()
+            .map(Some)
+    }
+
+    /// Models to load, either from `--models-config` or from the single-model flags.
+    pub fn model_configs(&self) -> Result<Vec<ModelConfig>> {
+        let models = match &self.models_config {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                serde_json::from_str::<Vec<ModelConfig>>(&contents)
+                    .with_context(|| format!("Failed to parse {}", path.display()))?
+            }
+            None => {
+                let name = self
+                    .served_model_name
+                    .clone()
+                    .or_else(|| self.model_id.clone())
+                    .or_else(|| {
+                        self.model_path
+                            .as_ref()
+                            .and_then(|path| path.file_name())
+                            .map(|name| name.to_string_lossy().into_owned())
+                    })
+                    .context(
+                        "Either --model-id, --model-path or --models-config must be provided",
+                    )?;
+
+                vec![ModelConfig {
+                    name,
+                    model_id: self.model_id.clone(),
+                    model_path: self.model_path.clone(),
+                    revision: self.model_revision.clone(),
+                    use_pth: self.use_pth,
+                    max_sequence_length: None,
+                    id2label: self.parse_id2label(),
+                    task: self.task,
+                    problem_type: self.problem_type,
+                    multi_label_threshold: None,
+                    label_thresholds: None,
+                    min_confidence: None,
+                    abstain_label: None,
+                    calibration: self.calibration.clone(),
+                    chunk_stride: None,
+                    max_chunks: None,
+                    workers: None,
+                    device: None,
+                }]
+            }
+        };
+
+        if models.is_empty() {
+            bail!("No models configured");
+        }
+
+        let mut seen = HashSet::new();
+        for model in &models {
+            if model.model_id.is_none() && model.model_path.is_none() {
+                bail!("Model `{}` needs either model_id or model_path", model.name);
+            }
+            if !seen.insert(model.name.clone()) {
+                bail!("Model `{}` is configured more than once", model.name);
+            }
+        }
+
+        Ok(models)
+    }
+
     pub fn server_address(&self) -> String {
         format!("{}:{}", self.host, self.port)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn devices(spec: &str) -> Vec<DeviceSpec> {
+        spec.parse::<DeviceList>().expect("valid device list").0
+    }
+
+    #[test]
+    fn parses_device_lists() {
+        assert_eq!(devices("cpu"), [DeviceSpec::Cpu]);
+        assert_eq!(devices("cuda"), [DeviceSpec::Cuda(0)]);
+        assert_eq!(devices("metal:1"), [DeviceSpec::Metal(1)]);
+        assert_eq!(
+            devices("cuda:0,1"),
+            [DeviceSpec::Cuda(0), DeviceSpec::Cuda(1)]
+        );
+        assert_eq!(
+            devices("cuda:2, 0 ,cpu,metal:1,3"),
+            [
+                DeviceSpec::Cuda(2),
+                DeviceSpec::Cuda(0),
+                DeviceSpec::Cpu,
+                DeviceSpec::Metal(1),
+                DeviceSpec::Metal(3)
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_device_lists() {
+        for spec in ["1,cuda:0", "cpu:0", "cpu,1", "gpu", "cuda:x", "cuda:-1", ""] {
+            assert!(spec.parse::<DeviceList>().is_err(), "accepted `{spec}`");
+        }
+    }
+
+    #[test]
+    fn device_lists_round_trip_through_display() {
+        let list = devices("cuda:0,1,cpu");
+        let spec = list
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+        assert_eq!(spec, "cuda:0,cuda:1,cpu");
+        assert_eq!(devices(&spec), list);
+    }
+
+    #[test]
+    fn batch_size_must_be_positive() {
+        assert!(Config::try_parse_from(["arbiter", "--batch-size", "0"]).is_err());
+        let config = Config::try_parse_from(["arbiter", "--batch-size", "1"]).unwrap();
+        assert_eq!(config.batch_size, 1);
+    }
+
+    fn label_thresholds(spec: &str) -> Result<Option<HashMap<String, f64>>> {
+        Config::try_parse_from(["arbiter", "--label-thresholds", spec])
+            .unwrap()
+            .parse_label_thresholds()
+    }
+
+    #[test]
+    fn parses_label_thresholds() {
+        let thresholds = label_thresholds("Claim=0.7, No Claim=0.5")
+            .unwrap()
+            .unwrap();
+        assert_eq!(thresholds.len(), 2);
+        assert_eq!(thresholds["Claim"], 0.7);
+        assert_eq!(thresholds["No Claim"], 0.5);
+        let config = Config::try_parse_from(["arbiter"]).unwrap();
+        assert_eq!(config.parse_label_thresholds().unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_malformed_label_thresholds() {
+        for (spec, entry) in [
+            ("Claim=0.7x", "`0.7x`"),
+            ("Claim:0.7", "`Claim:0.7`"),
+            ("Claim=0.7,No Claim", "`No Claim`"),
+            ("Claim=0.7,", "``"),
+        ] {
+            let err = label_thresholds(spec).expect_err(spec).to_string();
+            assert!(err.contains(entry), "`{spec}` gave `{err}`");
+        }
+    }
+
+    #[test]
+    fn deserializes_device_lists_from_strings() {
+        let list: DeviceList = serde_json::from_str(r#""cuda:0,1""#).unwrap();
+        assert_eq!(list.0, [DeviceSpec::Cuda(0), DeviceSpec::Cuda(1)]);
+        assert!(serde_json::from_str::<DeviceList>(r#""gpu""#).is_err());
+    }
+}
@@ -1,27 +1,45 @@
-use anyhow::{Result, bail};
-use async_trait::async_trait;
+use anyhow::{Context, Result, bail};
 use candle_core::utils::{cuda_is_available, metal_is_available};
 use candle_core::{Device, Tensor};
 use candle_nn::VarBuilder;
-use candle_nn::ops::softmax;
 use candle_transformers::models::debertav2::{
     Config as DebertaV2Config, DebertaV2SeqClassificationModel, Id2Label,
 };
 use chrono::Utc;
 use hf_hub::{Repo, RepoType, api::tokio::Api};
+use metrics::{counter, histogram};
+use serde::Deserialize;
 use std::collections::HashMap;
 use std::path::PathBuf;
-use tokenizers::{PaddingParams, Tokenizer};
+use tokenizers::utils::padding::pad_encodings;
+use tokenizers::{EncodeInput, Encoding, PaddingParams, PostProcessor, Tokenizer};
 use uuid::Uuid;
 
-use crate::engine::BatchedEngine;
-use crate::types::{ClassificationData, ClassificationRequest, ClassificationResponse, Usage};
+use crate::calibration::Calibration;
+use crate::config::{DeviceSpec, ProblemType};
+use crate::engine::{BatchedEngine, EngineError, InputResult, WorkItem};
+use crate::types::{
+    ChunkAggregation, ClassificationData, ClassificationOptions, ClassificationRequest,
+    ClassificationResponse, FunctionToApply, LabelScore, TextInput, Truncation, Usage,
+};
 
 pub struct DebertaBatchedEngine {
     model: DebertaV2SeqClassificationModel,
     tokenizer: Tokenizer,
+    /// Tokenizer without truncation nor padding, to count the tokens of long inputs
+    counting_tokenizer: Tokenizer,
     device: Device,
     id2label: Id2Label,
+    problem_type: ProblemType,
+    multi_label_threshold: f64,
+    /// Decision threshold of each label id
+    label_thresholds: HashMap<u32, f64>,
+    min_confidence: Option<f64>,
+    abstain_label: String,
+    calibration: Option<Calibration>,
+    /// Maximum number of windows of a chunked input
+    max_chunks: usize,
+    count_special_tokens: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -33,6 +51,27 @@ pub struct DebertaConfig {
     pub cpu: bool,
     pub max_sequence_length: usize,
     pub id2label: Option<HashMap<u32, String>>,
+    /// Overrides the `problem_type` in the model's config.json
+    pub problem_type: Option<ProblemType>,
+    pub multi_label_threshold: f64,
+    /// Minimum probability of each label, by label name, for it to be predicted
+    pub label_thresholds: HashMap<String, f64>,
+    /// Minimum probability of the predicted label
+    pub min_confidence: Option<f64>,
+    /// Label returned instead of a prediction below its threshold
+    pub abstain_label: String,
+    /// Calibration file applied to the logits before scoring
+    pub calibration: Option<PathBuf>,
+    /// Overlap in tokens between the windows of chunked inputs, an eighth of
+    /// `max_sequence_length` if unset
+    pub chunk_stride: Option<usize>,
+    /// Maximum number of windows of a chunked input
+    pub max_chunks: usize,
+    /// Whether special tokens count towards token usage
+    pub count_special_tokens: bool,
+    /// Device to load the model on; the first GPU found, or the CPU with
+    /// `cpu`, if unset
+    pub device: Option<DeviceSpec>,
 }
 
 impl Default for DebertaConfig {
@@ -45,240 +84,752 @@ impl Default for DebertaConfig {
             cpu: false,
             max_sequence_length: 512,
             id2label: None,
+            problem_type: None,
+            multi_label_threshold: 0.5,
+            label_thresholds: HashMap::new(),
+            min_confidence: None,
+            abstain_label: "UNCERTAIN".to_string(),
+            calibration: None,
+            chunk_stride: None,
+            max_chunks: 32,
+            count_special_tokens: true,
+            device: None,
         }
     }
 }
 
-impl DebertaBatchedEngine {
-    fn device(cpu: bool) -> Result<Device> {
-        if cpu {
-            Ok(Device::Cpu)
-        } else if metal_is_available() {
-            tracing::info!("Using metal acceleration");
-            Ok(Device::new_metal(0)?)
-        } else if cuda_is_available() {
-            tracing::info!("Using CUDA GPU acceleration");
-            Ok(Device::new_cuda(0)?)
-        } else {
-            tracing::info!(
-                "CUDA not available, running on CPU. To run on GPU, build with `--features cuda`"
-            );
-            Ok(Device::Cpu)
+/// Picks GPU `ordinal` of the available accelerator, wrapping around the
+/// GPUs found so that extra workers share them, falling back to CPU.
+pub fn default_device(cpu: bool, ordinal: usize) -> DeviceSpec {
+    if cpu {
+        DeviceSpec::Cpu
+    } else if metal_is_available() {
+        // Metal hosts have a single GPU
+        DeviceSpec::Metal(0)
+    } else if cuda_is_available() {
+        DeviceSpec::Cuda(ordinal % cuda_device_count())
+    } else {
+        tracing::info!(
+            "CUDA not available, running on CPU. To run on GPU, build with `--features cuda`"
+        );
+        DeviceSpec::Cpu
+    }
+}
+
+/// Number of CUDA GPUs on the host, at least one.
+#[cfg(feature = "cuda")]
+fn cuda_device_count() -> usize {
+    candle_core::cuda_backend::cudarc::driver::CudaContext::device_count()
+        .map_or(1, |count| count.max(1) as usize)
+}
+
+#[cfg(not(feature = "cuda"))]
+fn cuda_device_count() -> usize {
+    1
+}
+
+/// Opens `spec`, or the default device if unset.
+pub fn select_device(cpu: bool, spec: Option<DeviceSpec>) -> Result<Device> {
+    match spec.unwrap_or_else(|| default_device(cpu, 0)) {
+        DeviceSpec::Cpu => Ok(Device::Cpu),
+        DeviceSpec::Metal(ordinal) => {
+            tracing::info!(ordinal, "Using metal acceleration");
+            Device::new_metal(ordinal)
+                .with_context(|| format!("Failed to open metal device {ordinal}"))
+        }
+        DeviceSpec::Cuda(ordinal) => {
+            tracing::info!(ordinal, "Using CUDA GPU acceleration");
+            Device::new_cuda(ordinal)
+                .with_context(|| format!("Failed to open CUDA device {ordinal}"))
         }
     }
+}
 
-    #[tracing::instrument(skip(config), fields(model_id = ?config.model_id, cpu = config.cpu))]
-    pub async fn new(config: DebertaConfig) -> Result<Self> {
-        let device = Self::device(config.cpu)?;
-
-        // Get files from either the HuggingFace API, or from a specified local directory
-        let (config_filename, tokenizer_filename, weights_filename) = {
-            match &config.model_path {
-                Some(base_path) => {
-                    if !base_path.is_dir() {
-                        bail!("Model path {} is not a directory.", base_path.display());
-                    }
-
-                    let config_file = base_path.join("config.json");
-                    let tokenizer_file = base_path.join("tokenizer.json");
-                    let weights_file = if config.use_pth {
-                        base_path.join("pytorch_model.bin")
-                    } else {
-                        base_path.join("model.safetensors")
-                    };
-                    (config_file, tokenizer_file, weights_file)
-                }
-                None => {
-                    if config.model_id.is_none() {
-                        bail!("Either model_id or model_path must be specified");
-                    }
-
-                    let repo = Repo::with_revision(
-                        config.model_id.unwrap(),
-                        RepoType::Model,
-                        config.revision.clone(),
-                    );
-                    let api = Api::new()?;
-                    let api = api.repo(repo);
-                    let config_file = api.get("config.json").await?;
-                    let tokenizer_file = api.get("tokenizer.json").await?;
-                    let weights_file = if config.use_pth {
-                        api.get("pytorch_model.bin").await?
-                    } else {
-                        api.get("model.safetensors").await?
-                    };
-                    (config_file, tokenizer_file, weights_file)
+/// The files making up a DeBERTa checkpoint.
+pub struct ModelFiles {
+    pub config: PathBuf,
+    pub tokenizer: PathBuf,
+    pub weights: PathBuf,
+}
+
+impl ModelFiles {
+    /// Get files from either the HuggingFace API, or from a specified local directory
+    pub async fn fetch(config: &DebertaConfig) -> Result<Self> {
+        let (config_file, tokenizer_file, weights_file) = match &config.model_path {
+            Some(base_path) => {
+                if !base_path.is_dir() {
+                    bail!("Model path {} is not a directory.", base_path.display());
                 }
+
+                let config_file = base_path.join("config.json");
+                let tokenizer_file = base_path.join("tokenizer.json");
+                let weights_file = if config.use_pth {
+                    base_path.join("pytorch_model.bin")
+                } else {
+                    base_path.join("model.safetensors")
+                };
+                (config_file, tokenizer_file, weights_file)
+            }
+            None => {
+                let Some(model_id) = &config.model_id else {
+                    bail!("Either model_id or model_path must be specified");
+                };
+
+                let repo =
+                    Repo::with_revision(model_id.clone(), RepoType::Model, config.revision.clone());
+                let api = Api::new()?;
+                let api = api.repo(repo);
+                let config_file = api.get("config.json").await?;
+                let tokenizer_file = api.get("tokenizer.json").await?;
+                let weights_file = if config.use_pth {
+                    api.get("pytorch_model.bin").await?
+                } else {
+                    api.get("model.safetensors").await?
+                };
+                (config_file, tokenizer_file, weights_file)
             }
         };
 
-        let model_config = std::fs::read_to_string(config_filename)?;
-        let model_config: DebertaV2Config = serde_json::from_str(&model_config)?;
+        Ok(Self {
+            config: config_file,
+            tokenizer: tokenizer_file,
+            weights: weights_file,
+        })
+    }
 
-        // Command-line id2label takes precedence. Otherwise, use model config's id2label.
-        let id2label = if let Some(id2label) = config.id2label {
-            id2label
-        } else if let Some(id2label) = &model_config.id2label {
-            id2label.clone()
-        } else {
-            bail!("Id2Label not found in the model configuration nor specified as a parameter");
-        };
+    /// Reads config.json, both as the typed DeBERTa config and as raw JSON for
+    /// fields the typed config doesn't cover.
+    pub fn read_config(&self) -> Result<(DebertaV2Config, serde_json::Value)> {
+        let model_config = std::fs::read_to_string(&self.config)?;
+        let raw_model_config: serde_json::Value = serde_json::from_str(&model_config)?;
+        let model_config: DebertaV2Config = serde_json::from_value(raw_model_config.clone())?;
+        Ok((model_config, raw_model_config))
+    }
 
-        let mut tokenizer = Tokenizer::from_file(tokenizer_filename)
+    /// Loads the tokenizer, padding batches to their longest input and
+    /// truncating inputs to `max_sequence_length` tokens. The truncated tail of
+    /// an input is kept as overflowing windows overlapping by `stride` tokens.
+    pub fn load_tokenizer(&self, max_sequence_length: usize, stride: usize) -> Result<Tokenizer> {
+        let mut tokenizer = Tokenizer::from_file(&self.tokenizer)
             .map_err(|e| anyhow::anyhow!("Tokenizer error: {e}"))?;
         tokenizer.with_padding(Some(PaddingParams::default()));
+
+        // The tokenizer panics on a stride that isn't shorter than what a
+        // sequence is truncated to, which for a pair can be half the budget.
+        let pair_tokens = tokenizer
+            .get_post_processor()
+            .map_or(0, |processor| processor.added_tokens(true));
+        let shortest_truncation = max_sequence_length.saturating_sub(pair_tokens) / 2;
+        if stride > 0 && stride >= shortest_truncation {
+            bail!(
+                "Chunk stride {stride} must be less than {shortest_truncation} tokens for a maximum sequence length of {max_sequence_length}"
+            );
+        }
+
         tokenizer
             .with_truncation(Some(tokenizers::TruncationParams {
-                max_length: config.max_sequence_length,
+                max_length: max_sequence_length,
+                stride,
                 ..Default::default()
             }))
             .map_err(|e| anyhow::anyhow!("Tokenizer truncation error: {e}"))?;
+        Ok(tokenizer)
+    }
 
-        let vb = if config.use_pth {
+    /// Loads the weights onto `device`, scoped to the `deberta` prefix.
+    pub fn load_weights(&self, use_pth: bool, device: &Device) -> Result<VarBuilder<'static>> {
+        let vb = if use_pth {
             VarBuilder::from_pth(
-                &weights_filename,
+                &self.weights,
                 candle_transformers::models::debertav2::DTYPE,
-                &device,
+                device,
             )?
         } else {
             unsafe {
                 VarBuilder::from_mmaped_safetensors(
-                    &[weights_filename],
+                    &[&self.weights],
                     candle_transformers::models::debertav2::DTYPE,
-                    &device,
+                    device,
                 )?
             }
         };
 
-        let vb = vb.set_prefix("deberta");
+        Ok(vb.set_prefix("deberta"))
+    }
+}
+
+/// Command-line id2label takes precedence. Otherwise, use model config's id2label.
+pub fn resolve_id2label(
+    id2label: Option<Id2Label>,
+    model_config: &DebertaV2Config,
+) -> Result<Id2Label> {
+    if let Some(id2label) = id2label {
+        Ok(id2label)
+    } else if let Some(id2label) = &model_config.id2label {
+        Ok(id2label.clone())
+    } else {
+        bail!("Id2Label not found in the model configuration nor specified as a parameter");
+    }
+}
+
+/// Stacks a batch of encodings into `input_ids`, `attention_mask` and
+/// `token_type_ids` tensors, recording how much of the batch is padding.
+pub fn batch_tensors(encodings: &[Encoding], device: &Device) -> Result<(Tensor, Tensor, Tensor)> {
+    // Fraction of the padded batch that is padding
+    let padded_tokens = encodings.iter().map(Encoding::len).sum::<usize>();
+    if padded_tokens > 0 {
+        let real_tokens = encodings
+            .iter()
+            .flat_map(Encoding::get_attention_mask)
+            .filter(|&&mask| mask != 0)
+            .count();
+        histogram!("batch_padding_ratio").record(1.0 - real_tokens as f64 / padded_tokens as f64);
+    }
+
+    // Convert to tensors
+    let stack = |rows: Vec<&[u32]>| -> Result<Tensor> {
+        let tensors = rows
+            .into_iter()
+            .map(|row| Tensor::new(row, device))
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        Ok(Tensor::stack(&tensors, 0)?)
+    };
+
+    let input_ids = stack(encodings.iter().map(Encoding::get_ids).collect())?;
+    let attention_mask = stack(encodings.iter().map(Encoding::get_attention_mask).collect())?;
+    let token_type_ids = stack(encodings.iter().map(Encoding::get_type_ids).collect())?;
+
+    Ok((input_ids, attention_mask, token_type_ids))
+}
+
+impl DebertaBatchedEngine {
+    #[tracing::instrument(skip(config), fields(model_id = ?config.model_id, cpu = config.cpu, device = ?config.device))]
+    pub async fn new(config: DebertaConfig) -> Result<Self> {
+        let device = select_device(config.cpu, config.device)?;
+
+        let files = ModelFiles::fetch(&config).await?;
+        let (model_config, raw_model_config) = files.read_config()?;
+        let id2label = resolve_id2label(config.id2label, &model_config)?;
+
+        // Command-line problem type takes precedence. Otherwise, use model config's problem_type,
+        // treating a single-output head without one as regression like Hugging Face does.
+        let problem_type = match (config.problem_type, raw_model_config.get("problem_type")) {
+            (Some(problem_type), _) => problem_type,
+            (None, Some(value)) if !value.is_null() => ProblemType::deserialize(value)?,
+            (None, _) if id2label.len() == 1 => ProblemType::Regression,
+            (None, _) => ProblemType::default(),
+        };
+        tracing::info!(?problem_type, "Using problem type");
+
+        let calibration = match &config.calibration {
+            Some(_) if problem_type == ProblemType::Regression => {
+                bail!("Calibration is not supported for regression models")
+            }
+            Some(path) => Some(Calibration::load(path, id2label.len())?),
+            None => None,
+        };
+        if let Some(calibration) = &calibration {
+            tracing::info!(temperature = calibration.temperature, "Using calibration");
+        }
+
+        // Thresholds compare against probabilities, except for regression
+        // models whose scores are the raw outputs
+        let check_probability = |name: String, value: f64| {
+            if problem_type != ProblemType::Regression && !(0.0..=1.0).contains(&value) {
+                bail!("{name} must be between 0 and 1, got {value}");
+            }
+            Ok(())
+        };
+        check_probability(
+            "Multi-label threshold".to_string(),
+            config.multi_label_threshold,
+        )?;
+        if let Some(min_confidence) = config.min_confidence {
+            check_probability("Minimum confidence".to_string(), min_confidence)?;
+        }
+        for (label, &threshold) in &config.label_thresholds {
+            check_probability(format!("Threshold of label `{label}`"), threshold)?;
+        }
+
+        let label_ids: HashMap<&str, u32> = id2label
+            .iter()
+            .map(|(&id, label)| (label.as_str(), id))
+            .collect();
+        let label_thresholds = config
+            .label_thresholds
+            .iter()
+            .map(|(label, &threshold)| match label_ids.get(label.as_str()) {
+                Some(&id) => Ok((id, threshold)),
+                None => bail!("Label threshold given for unknown label `{label}`"),
+            })
+            .collect::<Result<HashMap<u32, f64>>>()?;
+
+        let chunk_stride = config
+            .chunk_stride
+            .unwrap_or(config.max_sequence_length / 8);
+        let tokenizer = files.load_tokenizer(config.max_sequence_length, chunk_stride)?;
+        let mut counting_tokenizer = tokenizer.clone();
+        counting_tokenizer
+            .with_truncation(None)
+            .map_err(|e| anyhow::anyhow!("Tokenizer truncation error: {e}"))?
+            .with_padding(None);
+        let vb = files.load_weights(config.use_pth, &device)?;
         let model =
             DebertaV2SeqClassificationModel::load(vb, &model_config, Some(id2label.clone()))?;
 
         Ok(Self {
             model,
             tokenizer,
+            counting_tokenizer,
             device,
             id2label,
+            problem_type,
+            multi_label_threshold: config.multi_label_threshold,
+            label_thresholds,
+            min_confidence: config.min_confidence,
+            abstain_label: config.abstain_label,
+            calibration,
+            max_chunks: config.max_chunks,
+            count_special_tokens: config.count_special_tokens,
         })
     }
-}
 
-#[async_trait]
-impl BatchedEngine for DebertaBatchedEngine {
-    #[tracing::instrument(skip(self, requests), fields(batch_size = requests.len()))]
-    async fn classify_batch(
-        &self,
-        requests: Vec<ClassificationRequest>,
-    ) -> Result<Vec<Result<ClassificationResponse>>> {
-        let mut all_texts = Vec::new();
-        let mut request_boundaries = Vec::new();
-        let mut current_index = 0;
-
-        // Flatten all input texts from all requests
-        for request in &requests {
-            request_boundaries.push((current_index, current_index + request.input.len()));
-            all_texts.extend_from_slice(&request.input);
-            current_index += request.input.len();
-        }
+    fn label_name(&self, id: u32) -> String {
+        self.id2label
+            .get(&id)
+            .cloned()
+            .unwrap_or_else(|| format!("LABEL_{id}"))
+    }
 
-        // Tokenize all texts in one batch
-        let tokenizer_clone = self.tokenizer.clone();
-        let (_, input_ids, attention_mask, token_type_ids) =
-            tokio::task::spawn_blocking(move || {
-                tokenizer_clone
-                    .encode_batch(all_texts, true)
-                    .map_err(|e| anyhow::anyhow!("Tokenization error: {e}"))
-                    .map(|encodings| {
-                        let mut encoding_stack = Vec::default();
-                        let mut attention_mask_stack = Vec::default();
-                        let mut token_type_id_stack = Vec::default();
-
-                        for encoding in &encodings {
-                            encoding_stack.push(encoding.get_ids().to_vec());
-                            attention_mask_stack.push(encoding.get_attention_mask().to_vec());
-                            token_type_id_stack.push(encoding.get_type_ids().to_vec());
-                        }
-
-                        (
-                            encodings,
-                            encoding_stack,
-                            attention_mask_stack,
-                            token_type_id_stack,
-                        )
-                    })
-            })
-            .await??;
+    /// Scores of one sequence from its raw logits, after calibration.
+    fn scores(&self, logits: &[f32], options: &ClassificationOptions) -> Vec<f32> {
+        let function = options
+            .function_to_apply
+            .unwrap_or(match self.problem_type {
+                ProblemType::SingleLabelClassification => FunctionToApply::Softmax,
+                ProblemType::MultiLabelClassification => FunctionToApply::Sigmoid,
+                ProblemType::Regression => FunctionToApply::None,
+            });
+        let calibrated = self
+            .calibration
+            .as_ref()
+            .map(|calibration| calibration.apply(logits));
+        apply_function(function, calibrated.as_deref().unwrap_or(logits))
+    }
 
-        // Convert to tensors
-        let input_ids_tensors: Result<Vec<_>> = input_ids
+    /// Builds the result for one input from the raw logits of each of its
+    /// chunks, of which there is one unless the input was chunked. Token counts
+    /// are left for the caller to fill in.
+    fn classification_data(
+        &self,
+        index: usize,
+        chunk_logits: &[Vec<f32>],
+        options: &ClassificationOptions,
+    ) -> ClassificationData {
+        let chunk_scores: Vec<Vec<f32>> = chunk_logits
             .iter()
-            .map(|ids| Tensor::new(ids.as_slice(), &self.device).map_err(anyhow::Error::from))
+            .map(|logits| self.scores(logits, options))
             .collect();
-        let attention_mask_tensors: Result<Vec<_>> = attention_mask
+        let (scores, logits) = match options.chunk_aggregation {
+            Some(aggregation) => self.aggregate_chunks(aggregation, &chunk_scores, chunk_logits),
+            None => (chunk_scores[0].clone(), chunk_logits[0].clone()),
+        };
+        let chunks = options.chunk_aggregation.map(|_| chunk_logits.len());
+        let logits = options
+            .return_logits
+            .then(|| logits.iter().map(|&x| x as f64).collect());
+
+        if self.problem_type == ProblemType::Regression {
+            return ClassificationData {
+                index,
+                label: None,
+                labels: None,
+                probs: None,
+                scores: None,
+                score: scores.first().map(|&x| x as f64),
+                num_classes: self.id2label.len(),
+                num_tokens: 0,
+                truncated: false,
+                chunks,
+                logits,
+            };
+        }
+
+        let labels = (self.problem_type == ProblemType::MultiLabelClassification)
+            .then(|| self.labels_above_threshold(&scores));
+        let (prediction, prob) = argmax(&scores);
+
+        ClassificationData {
+            index,
+            label: Some(self.decide(prediction as u32, prob)),
+            labels,
+            probs: Some(scores.iter().map(|&x| x as f64).collect()),
+            scores: self.ranked_scores(&scores, options),
+            score: None,
+            num_classes: self.id2label.len(),
+            num_tokens: 0,
+            truncated: false,
+            chunks,
+            logits,
+        }
+    }
+
+    /// Combines the scores and logits of the chunks of one input.
+    fn aggregate_chunks(
+        &self,
+        aggregation: ChunkAggregation,
+        scores: &[Vec<f32>],
+        logits: &[Vec<f32>],
+    ) -> (Vec<f32>, Vec<f32>) {
+        let chunk = |index: usize| (scores[index].clone(), logits[index].clone());
+        match aggregation {
+            ChunkAggregation::First => chunk(0),
+            ChunkAggregation::Mean => (column_mean(scores), column_mean(logits)),
+            ChunkAggregation::Max => (column_max(scores), column_max(logits)),
+            // The most confident chunk predicting a label other than the first,
+            // negative one; or the first chunk if every chunk is negative.
+            ChunkAggregation::AnyPositive
+                if self.problem_type == ProblemType::SingleLabelClassification =>
+            {
+                let positive = scores
+                    .iter()
+                    .map(|scores| argmax(scores))
+                    .enumerate()
+                    .filter(|&(_, (prediction, _))| prediction != 0)
+                    .max_by(|a, b| a.1.1.total_cmp(&b.1.1))
+                    .map_or(0, |(index, _)| index);
+                chunk(positive)
+            }
+            // Each label is on if it is on in any chunk
+            ChunkAggregation::AnyPositive => (column_max(scores), column_max(logits)),
+        }
+    }
+
+    /// The predicted label, or the abstain label when its probability is below
+    /// the minimum confidence or the label's own threshold.
+    fn decide(&self, prediction: u32, prob: f32) -> String {
+        let prob = prob as f64;
+        let below_min_confidence = self.min_confidence.is_some_and(|min| prob < min);
+        let below_threshold = self
+            .label_thresholds
+            .get(&prediction)
+            .is_some_and(|&threshold| prob < threshold);
+
+        if below_min_confidence || below_threshold {
+            self.abstain_label.clone()
+        } else {
+            self.label_name(prediction)
+        }
+    }
+
+    /// Labels whose probability reaches their threshold (or the multi-label
+    /// threshold), most likely first.
+    fn labels_above_threshold(&self, probs: &[f32]) -> Vec<String> {
+        let mut selected: Vec<(u32, f32)> = probs
             .iter()
-            .map(|mask| Tensor::new(mask.as_slice(), &self.device).map_err(anyhow::Error::from))
+            .enumerate()
+            .filter(|&(id, &prob)| {
+                let threshold = self.label_thresholds.get(&(id as u32));
+                prob as f64 >= threshold.copied().unwrap_or(self.multi_label_threshold)
+            })
+            .map(|(id, &prob)| (id as u32, prob))
             .collect();
-        let token_type_ids_tensors: Result<Vec<_>> = token_type_ids
+        selected.sort_by(|a, b| b.1.total_cmp(&a.1));
+        selected
+            .into_iter()
+            .map(|(id, _)| self.label_name(id))
+            .collect()
+    }
+
+    /// Labels with their scores, most likely first, limited to `top_k` unless
+    /// all scores are requested. `None` when neither is requested.
+    fn ranked_scores(
+        &self,
+        scores: &[f32],
+        options: &ClassificationOptions,
+    ) -> Option<Vec<LabelScore>> {
+        let limit = match (options.return_all_scores, options.top_k) {
+            (true, _) => scores.len(),
+            (false, Some(top_k)) => top_k,
+            (false, None) => return None,
+        };
+
+        let mut ranked: Vec<LabelScore> = scores
             .iter()
-            .map(|types| Tensor::new(types.as_slice(), &self.device).map_err(anyhow::Error::from))
+            .enumerate()
+            .map(|(id, &score)| LabelScore {
+                label: self.label_name(id as u32),
+                score: score as f64,
+            })
             .collect();
+        ranked.sort_by(|a, b| b.score.total_cmp(&a.score));
+        ranked.truncate(limit);
+        Some(ranked)
+    }
 
-        let input_ids = Tensor::stack(&input_ids_tensors?, 0)?;
-        let attention_mask = Tensor::stack(&attention_mask_tensors?, 0)?;
-        let token_type_ids = Tensor::stack(&token_type_ids_tensors?, 0)?;
+    pub fn id2label(&self) -> &Id2Label {
+        &self.id2label
+    }
+
+    pub fn problem_type(&self) -> ProblemType {
+        self.problem_type
+    }
+}
+
+/// How one input of a batch was encoded.
+pub struct EncodedInput {
+    /// Number of sequences the input occupies in the batch
+    pub chunks: usize,
+    /// Full length of the input, special tokens included
+    pub num_tokens: usize,
+    pub truncated: bool,
+    /// Tokens run through the model, as counted for usage
+    pub usage_tokens: usize,
+    /// Tokens cut off by truncation
+    pub truncated_tokens: usize,
+}
+
+/// One input tokenized ahead of batching: its unpadded sequences, the
+/// overflowing windows of a chunked input following the first, and how it was
+/// encoded.
+pub struct TokenizedInput {
+    pub sequences: Vec<Encoding>,
+    pub input: EncodedInput,
+}
+
+/// A tokenized batch: the padded sequences, chunked inputs followed by the
+/// overflowing windows of their tail, and how each input was encoded.
+pub struct EncodedBatch {
+    pub sequences: Vec<Encoding>,
+    pub inputs: Vec<EncodedInput>,
+}
+
+/// Number of tokens of `encoding` that aren't padding, leaving out special
+/// tokens unless `special_tokens` is set.
+pub fn count_tokens(encoding: &Encoding, special_tokens: bool) -> usize {
+    encoding
+        .get_attention_mask()
+        .iter()
+        .zip(encoding.get_special_tokens_mask())
+        .filter(|&(&mask, &special)| mask != 0 && (special_tokens || special == 0))
+        .count()
+}
+
+/// Index and value of the highest of `values`.
+fn argmax(values: &[f32]) -> (usize, f32) {
+    values
+        .iter()
+        .copied()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap_or((0, f32::NAN))
+}
+
+fn column_mean(rows: &[Vec<f32>]) -> Vec<f32> {
+    let mut sums = vec![0.0; rows[0].len()];
+    for row in rows {
+        for (sum, value) in sums.iter_mut().zip(row) {
+            *sum += value;
+        }
+    }
+    sums.into_iter()
+        .map(|sum| sum / rows.len() as f32)
+        .collect()
+}
+
+fn column_max(rows: &[Vec<f32>]) -> Vec<f32> {
+    let mut maxima = vec![f32::NEG_INFINITY; rows[0].len()];
+    for row in rows {
+        for (max, &value) in maxima.iter_mut().zip(row) {
+            *max = max.max(value);
+        }
+    }
+    maxima
+}
+
+/// Turns the logits of one input into scores, as the Hugging Face pipeline's
+/// `function_to_apply` does.
+fn apply_function(function: FunctionToApply, logits: &[f32]) -> Vec<f32> {
+    match function {
+        FunctionToApply::Softmax => {
+            let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let exp: Vec<f32> = logits.iter().map(|&x| (x - max).exp()).collect();
+            let sum: f32 = exp.iter().sum();
+            exp.into_iter().map(|x| x / sum).collect()
+        }
+        FunctionToApply::Sigmoid => logits.iter().map(|&x| 1.0 / (1.0 + (-x).exp())).collect(),
+        FunctionToApply::None => logits.to_vec(),
+    }
+}
+
+/// Encodes `input` as a single sequence, or as a pair with the tokenizer's pair
+/// template (setting token type ids) when it has a `text_pair`.
+pub fn encode_input(input: &TextInput) -> EncodeInput<'static> {
+    match &input.text_pair {
+        Some(text_pair) => (input.text.clone(), text_pair.clone()).into(),
+        None => input.text.clone().into(),
+    }
+}
+
+impl BatchedEngine for DebertaBatchedEngine {
+    type Request = ClassificationRequest;
+    type Tokenized = TokenizedInput;
+    type Encoded = EncodedBatch;
+    type Forwarded = Vec<Vec<f32>>;
+    type Output = InputResult<ClassificationData>;
+    type Response = ClassificationResponse;
+
+    fn tokenize(&self, request: &ClassificationRequest, index: usize) -> Result<TokenizedInput> {
+        let input = &request.input[index];
+        let tokenization_error = |e| EngineError::InvalidInput {
+            index,
+            message: format!("Tokenization error: {e}"),
+        };
+        let mut encoding = self
+            .tokenizer
+            .encode(encode_input(input), true)
+            .map_err(tokenization_error)?;
+        let overflowing = encoding.take_overflowing();
+
+        // Count the full length of an input that doesn't fit in a sequence
+        let num_tokens = if overflowing.is_empty() {
+            count_tokens(&encoding, true)
+        } else {
+            self.counting_tokenizer
+                .encode(encode_input(input), true)
+                .map_err(tokenization_error)?
+                .len()
+        };
+
+        let chunked = request.options.chunk_aggregation.is_some();
+        if chunked && 1 + overflowing.len() > self.max_chunks {
+            return Err(EngineError::InvalidInput {
+                index,
+                message: format!(
+                    "Input has {num_tokens} tokens and splits into {} windows, more than the maximum of {}",
+                    1 + overflowing.len(),
+                    self.max_chunks
+                ),
+            }
+            .into());
+        }
+        let truncated = !chunked && !overflowing.is_empty();
+        if truncated && request.options.truncation == Truncation::Error {
+            return Err(EngineError::InvalidInput {
+                index,
+                message: format!(
+                    "Input has {num_tokens} tokens, more than the maximum sequence length of {}",
+                    encoding.len()
+                ),
+            }
+            .into());
+        }
+
+        let mut tokenized = TokenizedInput {
+            input: EncodedInput {
+                chunks: 1,
+                num_tokens,
+                truncated,
+                usage_tokens: count_tokens(&encoding, self.count_special_tokens),
+                truncated_tokens: if truncated {
+                    num_tokens.saturating_sub(count_tokens(&encoding, true))
+                } else {
+                    0
+                },
+            },
+            sequences: vec![encoding],
+        };
+        if chunked {
+            tokenized.input.chunks += overflowing.len();
+            tokenized.input.usage_tokens += overflowing
+                .iter()
+                .map(|window| count_tokens(window, self.count_special_tokens))
+                .sum::<usize>();
+            tokenized.sequences.extend(overflowing);
+        }
+
+        Ok(tokenized)
+    }
+
+    fn sequence_lengths(&self, tokenized: &TokenizedInput) -> Vec<usize> {
+        tokenized.sequences.iter().map(Encoding::len).collect()
+    }
+
+    fn encode(&self, tokenized: Vec<TokenizedInput>) -> Result<EncodedBatch> {
+        let mut sequences = Vec::new();
+        let mut inputs = Vec::with_capacity(tokenized.len());
+        for input in tokenized {
+            sequences.extend(input.sequences);
+            inputs.push(input.input);
+        }
+        if let Some(padding) = self.tokenizer.get_padding() {
+            pad_encodings(&mut sequences, padding)
+                .map_err(|e| anyhow::anyhow!("Padding error: {e}"))?;
+        }
+
+        Ok(EncodedBatch { sequences, inputs })
+    }
+
+    #[tracing::instrument(skip(self, encoded), fields(sequences = encoded.sequences.len()))]
+    fn forward(&self, encoded: &EncodedBatch) -> Result<Vec<Vec<f32>>> {
+        let (input_ids, attention_mask, token_type_ids) =
+            batch_tensors(&encoded.sequences, &self.device)?;
 
-        // Run inference
         let logits = self
             .model
             .forward(&input_ids, Some(token_type_ids), Some(attention_mask))?;
-        let predictions = logits.argmax(1)?.to_vec1::<u32>()?;
-        let scores = softmax(&logits, 1)?.to_vec2::<f32>()?;
+        Ok(logits.to_vec2::<f32>()?)
+    }
 
-        let mut responses: Vec<Result<ClassificationResponse>> = Vec::new();
+    fn postprocess(
+        &self,
+        items: &[WorkItem<ClassificationRequest>],
+        encoded: EncodedBatch,
+        forwarded: Vec<Vec<f32>>,
+    ) -> Result<Vec<InputResult<ClassificationData>>> {
+        let mut raw_logits = forwarded.into_iter();
 
-        // Split results back into individual responses
-        for (req_idx, request) in requests.iter().enumerate() {
-            let (start_idx, end_idx) = request_boundaries[req_idx];
-            let request_predictions = &predictions[start_idx..end_idx];
-            let request_scores = &scores[start_idx..end_idx];
+        // Split results back into one output per input
+        let mut outputs = Vec::with_capacity(items.len());
+        for (item, input) in items.iter().zip(encoded.inputs) {
+            let chunk_logits: Vec<Vec<f32>> = raw_logits.by_ref().take(input.chunks).collect();
+            counter!("tokens_processed_total", "model" => item.request.model.clone())
+                .increment(input.usage_tokens as u64);
+            outputs.push(InputResult {
+                data: ClassificationData {
+                    num_tokens: input.num_tokens,
+                    truncated: input.truncated,
+                    ..self.classification_data(item.index, &chunk_logits, &item.request.options)
+                },
+                prompt_tokens: input.usage_tokens,
+                truncated_tokens: input.truncated_tokens,
+            });
+        }
 
-            let data: Vec<ClassificationData> = request_predictions
-                .iter()
-                .zip(request_scores.iter())
-                .enumerate()
-                .map(|(index, (&prediction, probs))| {
-                    let label = self
-                        .id2label
-                        .get(&prediction)
-                        .cloned()
-                        .unwrap_or_else(|| format!("LABEL_{prediction}"));
-
-                    ClassificationData {
-                        index,
-                        label,
-                        probs: probs.iter().map(|&x| x as f64).collect(),
-                        num_classes: self.id2label.len(),
-                    }
-                })
-                .collect();
-
-            let usage = Usage {
-                prompt_tokens: request.input.iter().map(|s| s.len() as u32 / 4).sum(),
-                total_tokens: request.input.iter().map(|s| s.len() as u32 / 4).sum(),
-                completion_tokens: 0,
-                prompt_tokens_details: None,
-            };
+        Ok(outputs)
+    }
 
-            responses.push(Ok(ClassificationResponse {
-                id: format!("classify-{}", Uuid::new_v4().simple()),
-                object: "list".to_string(),
-                created: Utc::now().timestamp(),
-                model: request.model.clone(),
-                data,
-                usage,
-            }));
+    fn respond(
+        &self,
+        request: &ClassificationRequest,
+        outputs: Vec<InputResult<ClassificationData>>,
+    ) -> ClassificationResponse {
+        let mut data = Vec::with_capacity(outputs.len());
+        let mut prompt_tokens = 0;
+        let mut truncated_tokens = 0;
+        for output in outputs {
+            data.push(output.data);
+            prompt_tokens += output.prompt_tokens;
+            truncated_tokens += output.truncated_tokens;
         }
 
-        Ok(responses)
+        ClassificationResponse {
+            id: format!("classify-{}", Uuid::new_v4().simple()),
+            object: "list".to_string(),
+            created: Utc::now().timestamp(),
+            model: request.model.clone(),
+            data,
+            usage: Usage::prompt(prompt_tokens as u32, truncated_tokens as u32),
+        }
     }
 }
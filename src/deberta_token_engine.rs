@@ -0,0 +1,185 @@
+use anyhow::Result;
+use candle_core::Device;
+use candle_nn::ops::softmax;
+use candle_transformers::models::debertav2::{DebertaV2NERModel, Id2Label};
+use chrono::Utc;
+use metrics::counter;
+use tokenizers::utils::padding::pad_encodings;
+use tokenizers::{Encoding, Tokenizer};
+use uuid::Uuid;
+
+use crate::deberta_engine::{
+    DebertaConfig, ModelFiles, batch_tensors, count_tokens, resolve_id2label, select_device,
+};
+use crate::engine::{BatchedEngine, EngineError, InputResult, WorkItem};
+use crate::token_aggregation::{self, TokenPrediction};
+use crate::types::{
+    TokenClassificationData, TokenClassificationRequest, TokenClassificationResponse, Usage,
+};
+
+/// A DeBERTa model with a token classification head, e.g. for named entity
+/// recognition.
+pub struct DebertaTokenClassificationEngine {
+    model: DebertaV2NERModel,
+    tokenizer: Tokenizer,
+    device: Device,
+    id2label: Id2Label,
+    count_special_tokens: bool,
+}
+
+impl DebertaTokenClassificationEngine {
+    #[tracing::instrument(skip(config), fields(model_id = ?config.model_id, cpu = config.cpu, device = ?config.device))]
+    pub async fn new(config: DebertaConfig) -> Result<Self> {
+        let device = select_device(config.cpu, config.device)?;
+
+        let files = ModelFiles::fetch(&config).await?;
+        let (model_config, _) = files.read_config()?;
+        let id2label = resolve_id2label(config.id2label, &model_config)?;
+
+        let tokenizer = files.load_tokenizer(config.max_sequence_length, 0)?;
+        let vb = files.load_weights(config.use_pth, &device)?;
+        let model = DebertaV2NERModel::load(vb, &model_config, Some(id2label.clone()))?;
+
+        Ok(Self {
+            model,
+            tokenizer,
+            device,
+            id2label,
+            count_special_tokens: config.count_special_tokens,
+        })
+    }
+
+    pub fn id2label(&self) -> &Id2Label {
+        &self.id2label
+    }
+}
+
+/// Pairs the label probabilities of each token of `encoding` with its
+/// character offsets, skipping special and padding tokens.
+fn token_predictions(encoding: &Encoding, scores: Vec<Vec<f32>>) -> Vec<TokenPrediction> {
+    scores
+        .into_iter()
+        .enumerate()
+        .filter(|&(index, _)| {
+            encoding.get_special_tokens_mask()[index] == 0
+                && encoding.get_attention_mask()[index] != 0
+        })
+        .map(|(index, scores)| {
+            let (start, end) = encoding.get_offsets()[index];
+            TokenPrediction {
+                index,
+                word_id: encoding.get_word_ids()[index],
+                start,
+                end,
+                scores,
+            }
+        })
+        .collect()
+}
+
+impl BatchedEngine for DebertaTokenClassificationEngine {
+    type Request = TokenClassificationRequest;
+    type Tokenized = Encoding;
+    type Encoded = Vec<Encoding>;
+    type Forwarded = Vec<Vec<Vec<f32>>>;
+    type Output = InputResult<TokenClassificationData>;
+    type Response = TokenClassificationResponse;
+
+    fn tokenize(&self, request: &TokenClassificationRequest, index: usize) -> Result<Encoding> {
+        // Offsets are counted in characters
+        self.tokenizer
+            .encode_char_offsets(request.input[index].as_str(), true)
+            .map_err(|e| {
+                EngineError::InvalidInput {
+                    index,
+                    message: format!("Tokenization error: {e}"),
+                }
+                .into()
+            })
+    }
+
+    fn sequence_lengths(&self, tokenized: &Encoding) -> Vec<usize> {
+        vec![tokenized.len()]
+    }
+
+    fn encode(&self, mut tokenized: Vec<Encoding>) -> Result<Vec<Encoding>> {
+        if let Some(padding) = self.tokenizer.get_padding() {
+            pad_encodings(&mut tokenized, padding)
+                .map_err(|e| anyhow::anyhow!("Padding error: {e}"))?;
+        }
+        Ok(tokenized)
+    }
+
+    #[tracing::instrument(skip(self, encoded), fields(sequences = encoded.len()))]
+    fn forward(&self, encoded: &Vec<Encoding>) -> Result<Vec<Vec<Vec<f32>>>> {
+        let (input_ids, attention_mask, token_type_ids) = batch_tensors(encoded, &self.device)?;
+
+        let logits = self
+            .model
+            .forward(&input_ids, Some(token_type_ids), Some(attention_mask))?;
+        Ok(softmax(&logits, 2)?.to_vec3::<f32>()?)
+    }
+
+    fn postprocess(
+        &self,
+        items: &[WorkItem<TokenClassificationRequest>],
+        encoded: Vec<Encoding>,
+        forwarded: Vec<Vec<Vec<f32>>>,
+    ) -> Result<Vec<InputResult<TokenClassificationData>>> {
+        // Split results back into one output per input
+        let mut outputs = Vec::with_capacity(items.len());
+        for (item, (encoding, scores)) in items.iter().zip(encoded.into_iter().zip(forwarded)) {
+            let request = &item.request;
+            let prompt_tokens = count_tokens(&encoding, self.count_special_tokens);
+            // Without a stride, overflowing windows hold exactly the cut off tokens
+            let truncated_tokens = encoding
+                .get_overflowing()
+                .iter()
+                .map(|window| count_tokens(window, false))
+                .sum::<usize>();
+            counter!("tokens_processed_total", "model" => request.model.clone())
+                .increment(prompt_tokens as u64);
+
+            outputs.push(InputResult {
+                data: TokenClassificationData {
+                    index: item.index,
+                    entities: token_aggregation::aggregate(
+                        &request.input[item.index],
+                        &token_predictions(&encoding, scores),
+                        &self.id2label,
+                        request.aggregation_strategy,
+                        &request.ignore_labels,
+                    ),
+                },
+                prompt_tokens,
+                truncated_tokens,
+            });
+        }
+
+        Ok(outputs)
+    }
+
+    fn respond(
+        &self,
+        request: &TokenClassificationRequest,
+        outputs: Vec<InputResult<TokenClassificationData>>,
+    ) -> TokenClassificationResponse {
+        let mut data = Vec::with_capacity(outputs.len());
+        let mut prompt_tokens = 0;
+        let mut truncated_tokens = 0;
+        for output in outputs {
+            data.push(output.data);
+            prompt_tokens += output.prompt_tokens;
+            truncated_tokens += output.truncated_tokens;
+        }
+
+        TokenClassificationResponse {
+            id: format!("tokenclassify-{}", Uuid::new_v4().simple()),
+            object: "list".to_string(),
+            created: Utc::now().timestamp(),
+            model: request.model.clone(),
+            data,
+            usage: Usage::prompt(prompt_tokens as u32, truncated_tokens as u32),
+        }
+    }
+}
@@ -1,6 +1,41 @@
-use crate::types::{ClassificationRequest, ClassificationResponse};
+use crate::types::{
+    ClassificationRequest, ClassificationResponse, TokenClassificationRequest,
+    TokenClassificationResponse,
+};
 use anyhow::Result;
 use async_trait::async_trait;
+use std::fmt;
+use std::sync::Arc;
+
+/// A request the batch processor can queue and split into its inputs, which
+/// may be batched with the inputs of other requests.
+pub trait BatchRequest: Send + Sync + 'static {
+    /// Number of inputs, each encoded as its own sequence
+    fn input_len(&self) -> usize;
+
+    /// Maximum time in milliseconds the request may wait for a result
+    fn timeout_ms(&self) -> Option<u64>;
+}
+
+impl BatchRequest for ClassificationRequest {
+    fn input_len(&self) -> usize {
+        self.input.len()
+    }
+
+    fn timeout_ms(&self) -> Option<u64> {
+        self.timeout_ms
+    }
+}
+
+impl BatchRequest for TokenClassificationRequest {
+    fn input_len(&self) -> usize {
+        self.input.len()
+    }
+
+    fn timeout_ms(&self) -> Option<u64> {
+        self.timeout_ms
+    }
+}
 
 #[async_trait]
 pub trait Engine {
@@ -8,9 +43,112 @@ pub trait Engine {
 }
 
 #[async_trait]
+pub trait TokenClassificationEngine {
+    async fn classify_tokens(
+        &self,
+        request: TokenClassificationRequest,
+    ) -> Result<TokenClassificationResponse>;
+}
+
+/// One input of a queued request; the unit the batch processor batches.
+#[derive(Debug)]
+pub struct WorkItem<Req> {
+    pub request: Arc<Req>,
+    /// Position of the input in the request
+    pub index: usize,
+}
+
+/// The result of one input, with the tokens it counts towards usage.
+#[derive(Debug)]
+pub struct InputResult<D> {
+    pub data: D,
+    pub prompt_tokens: usize,
+    pub truncated_tokens: usize,
+}
+
+/// A model run by the batch processor as a pipeline of three stages, so that
+/// one batch can be encoded while the previous one is on the device. Inputs
+/// are tokenized before they are queued.
 pub trait BatchedEngine: Send + Sync {
-    async fn classify_batch(
+    type Request: BatchRequest;
+    /// One input, tokenized ahead of batching
+    type Tokenized: Send + 'static;
+    /// A tokenized batch, ready to be sent to the device
+    type Encoded: Send + 'static;
+    /// The model outputs of a batch, copied back to the host
+    type Forwarded: Send + 'static;
+    type Output: Send + 'static;
+    type Response: Send + 'static;
+
+    /// Tokenizes input `index` of `request`. Called before the request is
+    /// queued, off the batch processor's task, so that an input that cannot
+    /// be encoded is rejected before it can fail a whole batch.
+    fn tokenize(&self, request: &Self::Request, index: usize) -> Result<Self::Tokenized>;
+
+    /// Number of tokens of each sequence a tokenized input encodes to, used
+    /// to keep batches under a padded-token budget.
+    fn sequence_lengths(&self, tokenized: &Self::Tokenized) -> Vec<usize>;
+
+    /// Pads the tokenized inputs of a batch, possibly from several requests,
+    /// into one encoded batch.
+    fn encode(&self, tokenized: Vec<Self::Tokenized>) -> Result<Self::Encoded>;
+
+    /// Runs the model on an encoded batch.
+    fn forward(&self, encoded: &Self::Encoded) -> Result<Self::Forwarded>;
+
+    /// Turns the model outputs of a batch into one output per item, in order.
+    fn postprocess(
         &self,
-        requests: Vec<ClassificationRequest>,
-    ) -> Result<Vec<Result<ClassificationResponse>>>;
+        items: &[WorkItem<Self::Request>],
+        encoded: Self::Encoded,
+        forwarded: Self::Forwarded,
+    ) -> Result<Vec<Self::Output>>;
+
+    /// Tokenizes a batch and runs it through all three stages in turn.
+    fn classify_batch(&self, items: &[WorkItem<Self::Request>]) -> Result<Vec<Self::Output>> {
+        let tokenized = items
+            .iter()
+            .map(|item| self.tokenize(&item.request, item.index))
+            .collect::<Result<Vec<_>>>()?;
+        let encoded = self.encode(tokenized)?;
+        let forwarded = self.forward(&encoded)?;
+        self.postprocess(items, encoded, forwarded)
+    }
+
+    /// Assembles the response to `request` from the outputs of all its
+    /// inputs, in order.
+    fn respond(&self, request: &Self::Request, outputs: Vec<Self::Output>) -> Self::Response;
+}
+
+/// Failures of the batching layer that callers may want to tell apart from
+/// model errors.
+#[derive(Debug)]
+pub enum EngineError {
+    /// The request's deadline passed before it was processed
+    DeadlineExceeded,
+    /// The queue is full; the request was rejected without being queued
+    Overloaded,
+    /// The request has more inputs than the queue can ever hold
+    TooManyInputs { inputs: usize, limit: usize },
+    /// The batch processor has stopped and can no longer serve requests
+    Unavailable,
+    /// Input `index` of the request cannot be processed by the model
+    InvalidInput { index: usize, message: String },
 }
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DeadlineExceeded => write!(f, "Request timed out before it could be processed"),
+            Self::Overloaded => write!(f, "Server is overloaded, retry later"),
+            Self::TooManyInputs { inputs, limit } => write!(
+                f,
+                "Request has {inputs} inputs, more than the queue holds ({limit})"
+            ),
+            Self::Unavailable => write!(f, "Model is not available"),
+            Self::InvalidInput { message, .. } => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
@@ -0,0 +1,193 @@
+use axum::{
+    async_trait,
+    extract::{FromRequest, Request, rejection::JsonRejection},
+    http::{HeaderValue, StatusCode, header},
+    response::{IntoResponse, Json, Response},
+};
+use serde::Serialize;
+
+use crate::engine::EngineError;
+
+/// An error returned to API clients as an OpenAI-style JSON body:
+/// `{"error": {"message", "type", "code", "param"}}`.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+    error_type: &'static str,
+    code: Option<&'static str>,
+    param: Option<String>,
+    retry_after_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: ErrorDetail<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorDetail<'a> {
+    message: &'a str,
+    #[serde(rename = "type")]
+    error_type: &'a str,
+    code: Option<&'a str>,
+    param: Option<&'a str>,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, error_type: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            error_type,
+            code: None,
+            param: None,
+            retry_after_secs: None,
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "invalid_request_error", message)
+    }
+
+    pub fn payload_too_large(message: impl Into<String>) -> Self {
+        Self::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "invalid_request_error",
+            message,
+        )
+    }
+
+    pub fn model_not_found(model: &str) -> Self {
+        Self::new(
+            StatusCode::NOT_FOUND,
+            "invalid_request_error",
+            format!("The model `{model}` does not exist"),
+        )
+        .with_code("model_not_found")
+        .with_param("model")
+    }
+
+    /// The requested model exists but can't serve this endpoint.
+    pub fn unsupported_model(message: impl Into<String>) -> Self {
+        Self::bad_request(message)
+            .with_code("unsupported_model")
+            .with_param("model")
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "invalid_request_error", message).with_code("not_found")
+    }
+
+    pub fn method_not_allowed(message: impl Into<String>) -> Self {
+        Self::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "invalid_request_error",
+            message,
+        )
+        .with_code("method_not_allowed")
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "server_error", message)
+    }
+
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn with_param(mut self, param: impl Into<String>) -> Self {
+        self.param = Some(param.into());
+        self
+    }
+}
+
+impl From<EngineError> for ApiError {
+    fn from(err: EngineError) -> Self {
+        let message = err.to_string();
+        match err {
+            EngineError::DeadlineExceeded => {
+                Self::new(StatusCode::GATEWAY_TIMEOUT, "timeout_error", message)
+                    .with_code("deadline_exceeded")
+            }
+            EngineError::Overloaded => Self {
+                retry_after_secs: Some(1),
+                ..Self::new(StatusCode::TOO_MANY_REQUESTS, "overloaded_error", message)
+                    .with_code("queue_full")
+            },
+            EngineError::TooManyInputs { .. } => {
+                Self::payload_too_large(message).with_code("too_many_inputs")
+            }
+            EngineError::Unavailable => {
+                Self::new(StatusCode::SERVICE_UNAVAILABLE, "server_error", message)
+                    .with_code("model_unavailable")
+            }
+            EngineError::InvalidInput { index, .. } => Self::bad_request(message)
+                .with_code("invalid_input")
+                .with_param(format!("input[{index}]")),
+        }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<EngineError>() {
+            Ok(err) => err.into(),
+            Err(err) => {
+                tracing::error!(error = %err, "Internal error");
+                Self::internal("The server failed to process the request")
+            }
+        }
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        // Bodies that parse but don't match the schema are reported as 400
+        // like any other invalid request, rather than axum's 422.
+        let status = match rejection {
+            JsonRejection::JsonDataError(_) => StatusCode::BAD_REQUEST,
+            _ => rejection.status(),
+        };
+        Self::new(status, "invalid_request_error", rejection.body_text()).with_code("invalid_json")
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: ErrorDetail {
+                message: &self.message,
+                error_type: self.error_type,
+                code: self.code,
+                param: self.param.as_deref(),
+            },
+        };
+
+        let mut response = (self.status, Json(body)).into_response();
+        if let Some(secs) = self.retry_after_secs {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+        response
+    }
+}
+
+/// `Json` extractor that reports malformed bodies as an [`ApiError`].
+pub struct ApiJson<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for ApiJson<T>
+where
+    Json<T>: FromRequest<S, Rejection = JsonRejection>,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(req, state).await?;
+        Ok(Self(value))
+    }
+}
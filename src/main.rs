@@ -1,13 +1,19 @@
 mod batched_engine;
+mod calibration;
 mod config;
 mod deberta_engine;
+mod deberta_token_engine;
 mod engine;
+mod error;
+mod registry;
+mod token_aggregation;
 mod types;
+mod validation;
+mod zero_shot;
 
 use axum::{
     Router,
     extract::State,
-    http::StatusCode,
     response::Json,
     routing::{get, post},
 };
@@ -18,11 +24,15 @@ use std::sync::Arc;
 use tokio::net::TcpListener;
 use tower_http::trace::TraceLayer;
 
-use batched_engine::BatchedEngineWrapper;
-use config::{BatchConfig, Config};
-use deberta_engine::{DebertaBatchedEngine, DebertaConfig};
-use engine::Engine;
-use types::{ClassificationRequest, ClassificationResponse, Usage};
+use config::{BatchConfig, Command, Config, RequestLimits};
+use error::{ApiError, ApiJson};
+use registry::ModelRegistry;
+use types::{
+    ClassificationOptions, ClassificationRequest, ClassificationResponse, TextInput,
+    TokenClassificationRequest, TokenClassificationResponse, ZeroShotRequest, ZeroShotResponse,
+};
+use validation::validate_inputs;
+use zero_shot::NliLabels;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -35,48 +45,37 @@ async fn main() -> anyhow::Result<()> {
         .init();
 
     let config = Config::parse();
-    tracing::info!("Starting inference server with config: {:?}", config);
-
-    // Validate that either model_id or model_path is provided
-    if config.model_id.is_none() && config.model_path.is_none() {
-        anyhow::bail!("Either --model-id or --model-path must be provided");
+    if let Some(Command::Calibrate(args)) = &config.command {
+        return calibration::run(&config, args).await;
     }
 
-    let batch_config = BatchConfig::from(&config);
-
-    let deberta_config = DebertaConfig {
-        model_id: config.model_id.clone(),
-        model_path: config.model_path.clone(),
-        revision: config.model_revision.clone(),
-        use_pth: config.use_pth,
-        cpu: config.cpu_only,
-        max_sequence_length: config.max_sequence_length,
-        id2label: config.parse_id2label(),
-    };
-
-    tracing::info!("Loading DeBERTa model...");
-    let deberta_engine = DebertaBatchedEngine::new(deberta_config).await?;
-    tracing::info!("Model loaded successfully");
+    tracing::info!("Starting inference server with config: {:?}", config);
 
-    let (engine, processor) = BatchedEngineWrapper::new(batch_config.clone(), deberta_engine);
-    tracing::info!("Batch engine wrapper created");
+    let batch_config = BatchConfig::from(&config);
 
-    // Spawn background task to process batches
-    tokio::spawn(async move {
-        tracing::info!("Starting batch processor");
-        if let Err(e) = processor.run_forever().await {
-            tracing::error!("Batch processor error: {}", e);
-        }
-    });
+    let registry = ModelRegistry::load(&config).await?;
+    tracing::info!(
+        models = ?registry.model_names().collect::<Vec<_>>(),
+        "Model registry loaded"
+    );
 
     let (prometheus_layer, metric_handle) = PrometheusMetricLayer::pair();
 
     let app = Router::new()
         .route("/classify", post(classify_handler))
+        .route("/zero-shot", post(zero_shot_handler))
+        .route("/token-classify", post(token_classify_handler))
         .route("/metrics", get(|| async move { metric_handle.render() }))
+        .fallback(|| async { ApiError::not_found("No route matches the request path") })
+        .method_not_allowed_fallback(|| async {
+            ApiError::method_not_allowed("The route does not support this method")
+        })
         .layer(prometheus_layer)
         .layer(TraceLayer::new_for_http())
-        .with_state(AppState::new(Arc::new(engine)));
+        .with_state(AppState::new(
+            Arc::new(registry),
+            RequestLimits::from(&config),
+        ));
 
     let listener = TcpListener::bind(&config.server_address()).await?;
     tracing::info!("Server running on http://{}", config.server_address());
@@ -92,78 +91,174 @@ async fn main() -> anyhow::Result<()> {
 
 #[derive(Clone)]
 struct AppState {
-    engine: Arc<dyn Engine + Send + Sync>,
+    registry: Arc<ModelRegistry>,
+    limits: Arc<RequestLimits>,
 }
 
 impl AppState {
-    fn new(engine: Arc<dyn Engine + Send + Sync>) -> Self {
-        Self { engine }
+    fn new(registry: Arc<ModelRegistry>, limits: RequestLimits) -> Self {
+        Self {
+            registry,
+            limits: Arc::new(limits),
+        }
     }
 }
 
 #[tracing::instrument(skip(state, request), fields(input_count = request.input.len(), model = %request.model))]
 async fn classify_handler(
     State(state): State<AppState>,
-    Json(request): Json<ClassificationRequest>,
-) -> Result<Json<ClassificationResponse>, StatusCode> {
+    ApiJson(request): ApiJson<ClassificationRequest>,
+) -> Result<Json<ClassificationResponse>, ApiError> {
     counter!("classification_requests_total").increment(1);
     tracing::info!("Processing classification request");
 
-    // Split the request into individual single-string requests
-    let individual_requests: Vec<ClassificationRequest> = request
-        .input
-        .iter()
-        .map(|text| ClassificationRequest {
+    let Some(model) = state.registry.get(&request.model) else {
+        tracing::warn!("Unknown model requested");
+        return Err(ApiError::model_not_found(&request.model));
+    };
+
+    let Some(engine) = model.classifier() else {
+        return Err(ApiError::unsupported_model(format!(
+            "The model `{}` is a token classification model, use /token-classify",
+            request.model
+        )));
+    };
+
+    validate_inputs(&request.input, &state.limits)?;
+    if request.options.top_k == Some(0) {
+        return Err(ApiError::bad_request("`top_k` must be at least 1").with_param("top_k"));
+    }
+
+    let response = engine.classify(request).await.map_err(|e| {
+        tracing::warn!(error = %e, "Classification failed");
+        ApiError::from(e)
+    })?;
+
+    tracing::info!("Classification completed successfully");
+    Ok(Json(response))
+}
+
+#[tracing::instrument(skip(state, request), fields(input_count = request.input.len(), label_count = request.candidate_labels.len(), model = %request.model))]
+async fn zero_shot_handler(
+    State(state): State<AppState>,
+    ApiJson(request): ApiJson<ZeroShotRequest>,
+) -> Result<Json<ZeroShotResponse>, ApiError> {
+    counter!("zero_shot_requests_total").increment(1);
+    tracing::info!("Processing zero-shot request");
+
+    let Some(model) = state.registry.get(&request.model) else {
+        tracing::warn!("Unknown model requested");
+        return Err(ApiError::model_not_found(&request.model));
+    };
+
+    let (Some(engine), Some(nli)) = (
+        model.classifier(),
+        NliLabels::from_id2label(&model.id2label),
+    ) else {
+        return Err(ApiError::unsupported_model(format!(
+            "The model `{}` has no entailment label and cannot be used for zero-shot classification",
+            request.model
+        )));
+    };
+
+    let texts: Vec<TextInput> = request.input.iter().cloned().map(TextInput::from).collect();
+    validate_inputs(&texts, &state.limits)?;
+
+    if request.candidate_labels.is_empty() {
+        return Err(
+            ApiError::bad_request("`candidate_labels` must contain at least one label")
+                .with_param("candidate_labels"),
+        );
+    }
+    if let Some(index) = request.candidate_labels.iter().position(String::is_empty) {
+        return Err(
+            ApiError::bad_request(format!("Candidate label {index} is empty"))
+                .with_param(format!("candidate_labels[{index}]")),
+        );
+    }
+    if !request.hypothesis_template.contains("{}") {
+        return Err(ApiError::bad_request(
+            "`hypothesis_template` must contain `{}` where the label goes",
+        )
+        .with_param("hypothesis_template"));
+    }
+
+    let pair_count = request.input.len() * request.candidate_labels.len();
+    if pair_count > state.limits.max_inputs {
+        return Err(ApiError::payload_too_large(format!(
+            "Request expands to {pair_count} text/label pairs, the maximum is {}",
+            state.limits.max_inputs
+        ))
+        .with_code("too_many_inputs")
+        .with_param("candidate_labels"));
+    }
+
+    let pairs = zero_shot::expand_pairs(
+        &request.input,
+        &request.candidate_labels,
+        &request.hypothesis_template,
+    );
+    let pair_response = engine
+        .classify(ClassificationRequest {
             model: request.model.clone(),
-            input: vec![text.clone()],
+            input: pairs,
+            timeout_ms: request.timeout_ms,
+            options: ClassificationOptions {
+                return_logits: true,
+                ..Default::default()
+            },
         })
-        .collect();
-
-    // Process all individual requests concurrently
-    let futures = individual_requests
-        .into_iter()
-        .map(|req| state.engine.classify(req));
-
-    let results = futures::future::join_all(futures).await;
-
-    // Check for any errors and collect successful responses
-    let mut all_data = Vec::new();
-    let mut total_prompt_tokens = 0;
-    let mut total_completion_tokens = 0;
-
-    for (index, result) in results.into_iter().enumerate() {
-        match result {
-            Ok(response) => {
-                // Add the classification data, adjusting the index to match original position
-                for mut data in response.data {
-                    data.index = index;
-                    all_data.push(data);
-                }
-                total_prompt_tokens += response.usage.prompt_tokens;
-                total_completion_tokens += response.usage.completion_tokens;
-            }
-            Err(e) => {
-                tracing::error!(input_index = index, error = %e, "Classification failed");
-                return Err(StatusCode::INTERNAL_SERVER_ERROR);
-            }
-        }
-    }
+        .await
+        .map_err(|e| {
+            tracing::warn!(error = %e, "Zero-shot classification failed");
+            ApiError::from(zero_shot::pair_error(e, request.candidate_labels.len()))
+        })?;
 
-    // Create the merged response
-    let merged_response = ClassificationResponse {
-        id: format!("classify-{}", uuid::Uuid::new_v4().simple()),
+    let data = zero_shot::rank_labels(
+        &pair_response.data,
+        &request.candidate_labels,
+        nli,
+        request.multi_label,
+    );
+
+    let response = ZeroShotResponse {
+        id: format!("zeroshot-{}", uuid::Uuid::new_v4().simple()),
         object: "list".to_string(),
         created: chrono::Utc::now().timestamp(),
         model: request.model,
-        data: all_data,
-        usage: Usage {
-            prompt_tokens: total_prompt_tokens,
-            total_tokens: total_prompt_tokens + total_completion_tokens,
-            completion_tokens: total_completion_tokens,
-            prompt_tokens_details: None,
-        },
+        data,
+        usage: pair_response.usage,
     };
 
-    tracing::info!("Classification completed successfully");
-    Ok(Json(merged_response))
+    tracing::info!("Zero-shot classification completed successfully");
+    Ok(Json(response))
+}
+
+#[tracing::instrument(skip(state, request), fields(input_count = request.input.len(), model = %request.model))]
+async fn token_classify_handler(
+    State(state): State<AppState>,
+    ApiJson(request): ApiJson<TokenClassificationRequest>,
+) -> Result<Json<TokenClassificationResponse>, ApiError> {
+    counter!("token_classification_requests_total").increment(1);
+    tracing::info!("Processing token classification request");
+
+    let Some(model) = state.registry.get(&request.model) else {
+        tracing::warn!("Unknown model requested");
+        return Err(ApiError::model_not_found(&request.model));
+    };
+
+    let Some(engine) = model.token_classifier() else {
+        return Err(ApiError::unsupported_model(format!(
+            "The model `{}` is not a token classification model",
+            request.model
+        )));
+    };
+
+    let texts: Vec<TextInput> = request.input.iter().cloned().map(TextInput::from).collect();
+    validate_inputs(&texts, &state.limits)?;
+
+    let response = engine.classify_tokens(request).await?;
+
+    tracing::info!("Token classification completed successfully");
+    Ok(Json(response))
 }
@@ -0,0 +1,223 @@
+use anyhow::{Result, bail};
+use candle_transformers::models::debertav2::Id2Label;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::batched_engine::{BatchProcessor, BatchedEngineWrapper};
+use crate::config::{BatchConfig, Config, DeviceList, DeviceSpec, ModelConfig, Task};
+use crate::deberta_engine::{DebertaBatchedEngine, DebertaConfig, default_device};
+use crate::deberta_token_engine::DebertaTokenClassificationEngine;
+use crate::engine::{BatchedEngine, Engine, TokenClassificationEngine};
+
+/// The set of models served by this process, keyed by the name clients put in
+/// the `model` field of a request.
+pub struct ModelRegistry {
+    models: HashMap<String, ServedModel>,
+}
+
+/// A loaded model and what handlers need to know about its outputs.
+pub struct ServedModel {
+    pub engine: ModelEngine,
+    pub id2label: Id2Label,
+}
+
+/// The engine of a served model, depending on its task.
+pub enum ModelEngine {
+    TextClassification(Arc<dyn Engine + Send + Sync>),
+    TokenClassification(Arc<dyn TokenClassificationEngine + Send + Sync>),
+}
+
+impl ServedModel {
+    /// The engine of a text classification model.
+    pub fn classifier(&self) -> Option<&(dyn Engine + Send + Sync)> {
+        match &self.engine {
+            ModelEngine::TextClassification(engine) => Some(engine.as_ref()),
+            ModelEngine::TokenClassification(_) => None,
+        }
+    }
+
+    /// The engine of a token classification model.
+    pub fn token_classifier(&self) -> Option<&(dyn TokenClassificationEngine + Send + Sync)> {
+        match &self.engine {
+            ModelEngine::TokenClassification(engine) => Some(engine.as_ref()),
+            ModelEngine::TextClassification(_) => None,
+        }
+    }
+}
+
+impl ModelRegistry {
+    /// Loads every configured model and spawns a batch processor for each.
+    pub async fn load(config: &Config) -> Result<Self> {
+        let batch_config = BatchConfig::from(config);
+        let mut models = HashMap::new();
+
+        for model in config.model_configs()? {
+            let deberta_config = deberta_config(config, &model)?;
+            let workers = model.workers.unwrap_or(config.workers);
+            if workers == 0 {
+                bail!("Model `{}` needs at least one worker", model.name);
+            }
+            let devices = worker_devices(config, &model, workers);
+
+            tracing::info!(model = %model.name, task = ?model.task, workers = devices.len(), "Loading DeBERTa model...");
+            let served_model = match model.task {
+                Task::TextClassification => {
+                    let mut deberta_engines = Vec::with_capacity(devices.len());
+                    for &device in &devices {
+                        deberta_engines.push(
+                            DebertaBatchedEngine::new(worker_config(&deberta_config, device))
+                                .await?,
+                        );
+                    }
+                    let id2label = deberta_engines[0].id2label().clone();
+                    let (engine, processor) = BatchedEngineWrapper::new(
+                        batch_config.clone(),
+                        model.name.clone(),
+                        deberta_engines,
+                    );
+                    spawn_processor(model.name.clone(), processor);
+                    ServedModel {
+                        engine: ModelEngine::TextClassification(Arc::new(engine)),
+                        id2label,
+                    }
+                }
+                Task::TokenClassification => {
+                    let mut deberta_engines = Vec::with_capacity(devices.len());
+                    for &device in &devices {
+                        deberta_engines.push(
+                            DebertaTokenClassificationEngine::new(worker_config(
+                                &deberta_config,
+                                device,
+                            ))
+                            .await?,
+                        );
+                    }
+                    let id2label = deberta_engines[0].id2label().clone();
+                    let (engine, processor) = BatchedEngineWrapper::new(
+                        batch_config.clone(),
+                        model.name.clone(),
+                        deberta_engines,
+                    );
+                    spawn_processor(model.name.clone(), processor);
+                    ServedModel {
+                        engine: ModelEngine::TokenClassification(Arc::new(engine)),
+                        id2label,
+                    }
+                }
+            };
+            tracing::info!(model = %model.name, "Model loaded successfully");
+
+            models.insert(model.name, served_model);
+        }
+
+        Ok(Self { models })
+    }
+
+    pub fn get(&self, model: &str) -> Option<&ServedModel> {
+        self.models.get(model)
+    }
+
+    pub fn model_names(&self) -> impl Iterator<Item = &str> {
+        self.models.keys().map(String::as_str)
+    }
+}
+
+/// Spawns the background task processing the batches of model `name`.
+fn spawn_processor<T: BatchedEngine + 'static>(name: String, processor: BatchProcessor<T>) {
+    tokio::spawn(async move {
+        tracing::info!(model = %name, "Starting batch processor");
+        if let Err(e) = processor.run_forever().await {
+            tracing::error!(model = %name, "Batch processor error: {}", e);
+        }
+    });
+}
+
+/// Device of each batch worker of `model`: `workers` replicas on every
+/// listed device, or worker N on GPU N, wrapping around the GPUs found, when
+/// no device is listed.
+fn worker_devices(config: &Config, model: &ModelConfig, workers: usize) -> Vec<DeviceSpec> {
+    match model.device.as_ref().or(config.device.as_ref()) {
+        Some(DeviceList(devices)) => (0..workers).flat_map(|_| devices.iter().copied()).collect(),
+        None => (0..workers)
+            .map(|worker| default_device(config.cpu_only, worker))
+            .collect(),
+    }
+}
+
+/// Engine settings of a batch worker running on `device`.
+fn worker_config(config: &DebertaConfig, device: DeviceSpec) -> DebertaConfig {
+    DebertaConfig {
+        device: Some(device),
+        ..config.clone()
+    }
+}
+
+/// Engine settings for `model`, falling back to the global flags.
+pub fn deberta_config(config: &Config, model: &ModelConfig) -> Result<DebertaConfig> {
+    let label_thresholds = match &model.label_thresholds {
+        Some(thresholds) => Some(thresholds.clone()),
+        None => config.parse_label_thresholds()?,
+    };
+
+    Ok(DebertaConfig {
+        model_id: model.model_id.clone(),
+        model_path: model.model_path.clone(),
+        revision: model.revision.clone(),
+        use_pth: model.use_pth,
+        cpu: config.cpu_only,
+        max_sequence_length: model
+            .max_sequence_length
+            .unwrap_or(config.max_sequence_length),
+        id2label: model.id2label.clone(),
+        problem_type: model.problem_type.or(config.problem_type),
+        multi_label_threshold: model
+            .multi_label_threshold
+            .unwrap_or(config.multi_label_threshold),
+        label_thresholds: label_thresholds.unwrap_or_default(),
+        min_confidence: model.min_confidence.or(config.min_confidence),
+        abstain_label: model
+            .abstain_label
+            .clone()
+            .unwrap_or_else(|| config.abstain_label.clone()),
+        calibration: model.calibration.clone(),
+        chunk_stride: model.chunk_stride.or(config.chunk_stride),
+        max_chunks: model.max_chunks.unwrap_or(config.max_chunks),
+        count_special_tokens: !config.exclude_special_tokens,
+        device: model
+            .device
+            .as_ref()
+            .or(config.device.as_ref())
+            .and_then(|devices| devices.0.first().copied()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn devices(args: &[&str], workers: usize) -> Vec<DeviceSpec> {
+        let config =
+            Config::try_parse_from(["arbiter", "--model-id", "model"].iter().chain(args)).unwrap();
+        let model = config.model_configs().unwrap().remove(0);
+        worker_devices(&config, &model, workers)
+    }
+
+    #[test]
+    fn workers_are_repeated_over_listed_devices() {
+        assert_eq!(
+            devices(&["--device", "cuda:0,1"], 2),
+            [
+                DeviceSpec::Cuda(0),
+                DeviceSpec::Cuda(1),
+                DeviceSpec::Cuda(0),
+                DeviceSpec::Cuda(1)
+            ]
+        );
+    }
+
+    #[test]
+    fn cpu_only_workers_all_run_on_the_cpu() {
+        assert_eq!(devices(&["--cpu-only"], 3), [DeviceSpec::Cpu; 3]);
+    }
+}
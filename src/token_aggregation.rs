@@ -0,0 +1,328 @@
+use candle_transformers::models::debertav2::Id2Label;
+
+use crate::types::{AggregationStrategy, Entity};
+
+/// The model's output for one token of an input.
+#[derive(Debug, Clone)]
+pub struct TokenPrediction {
+    /// Position of the token in the encoded input
+    pub index: usize,
+    /// Word of the input the token belongs to
+    pub word_id: Option<u32>,
+    /// Character offsets of the token in the input
+    pub start: usize,
+    pub end: usize,
+    /// Probability of each label
+    pub scores: Vec<f32>,
+}
+
+/// A token or word with its predicted label, before grouping into entities.
+struct Labeled {
+    label: String,
+    score: f64,
+    start: usize,
+    end: usize,
+    index: usize,
+}
+
+/// Turns the token predictions of `text` into the entities returned to
+/// clients, dropping those labeled with one of `ignore_labels`.
+pub fn aggregate(
+    text: &str,
+    tokens: &[TokenPrediction],
+    id2label: &Id2Label,
+    strategy: AggregationStrategy,
+    ignore_labels: &[String],
+) -> Vec<Entity> {
+    let labeled = match strategy {
+        AggregationStrategy::None | AggregationStrategy::Simple => tokens
+            .iter()
+            .map(|token| label_token(token, &token.scores, id2label))
+            .collect(),
+        AggregationStrategy::First | AggregationStrategy::Max => {
+            label_words(tokens, id2label, strategy)
+        }
+    };
+
+    if strategy == AggregationStrategy::None {
+        return labeled
+            .into_iter()
+            .filter(|token| !ignore_labels.contains(&token.label))
+            .map(|token| Entity {
+                word: slice_chars(text, token.start, token.end),
+                entity: Some(token.label),
+                entity_group: None,
+                score: token.score,
+                start: token.start,
+                end: token.end,
+                index: Some(token.index),
+            })
+            .collect();
+    }
+
+    group_entities(&labeled)
+        .into_iter()
+        .map(|group| group_entity(text, group))
+        .filter(|entity| {
+            !ignore_labels
+                .iter()
+                .any(|label| entity.entity_group.as_ref() == Some(label))
+        })
+        .collect()
+}
+
+fn label_token(token: &TokenPrediction, scores: &[f32], id2label: &Id2Label) -> Labeled {
+    let (id, score) = scores
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .map(|(id, &score)| (id as u32, score))
+        .unwrap_or_default();
+
+    Labeled {
+        label: id2label
+            .get(&id)
+            .cloned()
+            .unwrap_or_else(|| format!("LABEL_{id}")),
+        score: score as f64,
+        start: token.start,
+        end: token.end,
+        index: token.index,
+    }
+}
+
+/// Labels each word from the scores of its first token, or of its highest
+/// scoring token.
+fn label_words(
+    tokens: &[TokenPrediction],
+    id2label: &Id2Label,
+    strategy: AggregationStrategy,
+) -> Vec<Labeled> {
+    tokens
+        .chunk_by(|a, b| a.word_id.is_some() && a.word_id == b.word_id)
+        .map(|word| {
+            let scoring_token = match strategy {
+                AggregationStrategy::Max => word
+                    .iter()
+                    .max_by(|a, b| max_score(a).total_cmp(&max_score(b)))
+                    .unwrap_or(&word[0]),
+                _ => &word[0],
+            };
+            let last = &word[word.len() - 1];
+
+            Labeled {
+                end: last.end,
+                ..label_token(&word[0], &scoring_token.scores, id2label)
+            }
+        })
+        .collect()
+}
+
+fn max_score(token: &TokenPrediction) -> f32 {
+    token
+        .scores
+        .iter()
+        .copied()
+        .fold(f32::NEG_INFINITY, f32::max)
+}
+
+/// Splits a label into its `B`/`I` prefix and entity type; labels without a
+/// prefix are treated as inside an entity of that type.
+fn split_tag(label: &str) -> (bool, &str) {
+    if let Some(tag) = label.strip_prefix("B-") {
+        (true, tag)
+    } else if let Some(tag) = label.strip_prefix("I-") {
+        (false, tag)
+    } else {
+        (false, label)
+    }
+}
+
+/// Groups adjacent tokens of the same entity type, starting a new group at
+/// every `B-` tag.
+fn group_entities(labeled: &[Labeled]) -> Vec<&[Labeled]> {
+    labeled
+        .chunk_by(|previous, current| {
+            let (_, previous_tag) = split_tag(&previous.label);
+            let (begin, tag) = split_tag(&current.label);
+            tag == previous_tag && !begin
+        })
+        .collect()
+}
+
+fn group_entity(text: &str, group: &[Labeled]) -> Entity {
+    let first = &group[0];
+    let last = &group[group.len() - 1];
+    let (_, tag) = split_tag(&first.label);
+
+    Entity {
+        entity: None,
+        entity_group: Some(tag.to_string()),
+        score: group.iter().map(|token| token.score).sum::<f64>() / group.len() as f64,
+        word: slice_chars(text, first.start, last.end),
+        start: first.start,
+        end: last.end,
+        index: None,
+    }
+}
+
+fn slice_chars(text: &str, start: usize, end: usize) -> String {
+    text.chars()
+        .skip(start)
+        .take(end.saturating_sub(start))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LABELS: [&str; 5] = ["O", "B-PER", "I-PER", "B-LOC", "I-LOC"];
+
+    fn id2label() -> Id2Label {
+        LABELS
+            .iter()
+            .enumerate()
+            .map(|(id, label)| (id as u32, label.to_string()))
+            .collect()
+    }
+
+    /// A token of word `word_id` spanning characters `start..end`, with most
+    /// of the probability on `label`.
+    fn token(
+        index: usize,
+        word_id: u32,
+        start: usize,
+        end: usize,
+        label: &str,
+        score: f32,
+    ) -> TokenPrediction {
+        let rest = (1.0 - score) / (LABELS.len() - 1) as f32;
+        TokenPrediction {
+            index,
+            word_id: Some(word_id),
+            start,
+            end,
+            scores: LABELS
+                .iter()
+                .map(|&name| if name == label { score } else { rest })
+                .collect(),
+        }
+    }
+
+    /// Entity type, word and span of each entity.
+    fn spans(entities: &[Entity]) -> Vec<(&str, &str, usize, usize)> {
+        entities
+            .iter()
+            .map(|entity| {
+                let label = entity.entity_group.as_ref().or(entity.entity.as_ref());
+                (
+                    label.map_or("", String::as_str),
+                    entity.word.as_str(),
+                    entity.start,
+                    entity.end,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn simple_merges_begin_and_inside_tags() {
+        let text = "John Smith in Paris";
+        let tokens = [
+            token(1, 0, 0, 4, "B-PER", 0.9),
+            token(2, 1, 5, 10, "I-PER", 0.8),
+            token(3, 2, 11, 13, "O", 0.99),
+            token(4, 3, 14, 19, "B-LOC", 0.7),
+        ];
+
+        let entities = aggregate(
+            text,
+            &tokens,
+            &id2label(),
+            AggregationStrategy::Simple,
+            &["O".to_string()],
+        );
+
+        assert_eq!(
+            spans(&entities),
+            [("PER", "John Smith", 0, 10), ("LOC", "Paris", 14, 19)]
+        );
+        assert!((entities[0].score - 0.85).abs() < 1e-6);
+        assert!(entities.iter().all(|entity| entity.index.is_none()));
+    }
+
+    #[test]
+    fn none_keeps_one_result_per_token() {
+        let text = "John Smith in Paris";
+        let tokens = [
+            token(1, 0, 0, 4, "B-PER", 0.9),
+            token(2, 1, 5, 10, "I-PER", 0.8),
+            token(3, 2, 11, 13, "O", 0.99),
+            token(4, 3, 14, 19, "B-LOC", 0.7),
+        ];
+
+        let entities = aggregate(
+            text,
+            &tokens,
+            &id2label(),
+            AggregationStrategy::None,
+            &["O".to_string()],
+        );
+
+        assert_eq!(
+            spans(&entities),
+            [
+                ("B-PER", "John", 0, 4),
+                ("I-PER", "Smith", 5, 10),
+                ("B-LOC", "Paris", 14, 19)
+            ]
+        );
+        assert_eq!(entities[2].index, Some(4));
+    }
+
+    #[test]
+    fn sub_word_tokens_take_the_label_of_the_first_or_best_token() {
+        let text = "Washington";
+        let tokens = [
+            token(1, 0, 0, 4, "B-LOC", 0.6),
+            token(2, 0, 4, 10, "B-PER", 0.9),
+        ];
+
+        let first = aggregate(text, &tokens, &id2label(), AggregationStrategy::First, &[]);
+        assert_eq!(spans(&first), [("LOC", "Washington", 0, 10)]);
+        assert!((first[0].score - 0.6).abs() < 1e-6);
+
+        let max = aggregate(text, &tokens, &id2label(), AggregationStrategy::Max, &[]);
+        assert_eq!(spans(&max), [("PER", "Washington", 0, 10)]);
+        assert!((max[0].score - 0.9).abs() < 1e-6);
+    }
+
+    #[test]
+    fn words_are_sliced_by_character_offsets() {
+        let text = "Zoë lives in Zürich";
+        assert_eq!(slice_chars(text, 0, 3), "Zoë");
+        assert_eq!(slice_chars(text, 13, 19), "Zürich");
+        assert_eq!(slice_chars(text, 13, 100), "Zürich");
+        assert_eq!(slice_chars(text, 5, 3), "");
+
+        let tokens = [
+            token(1, 0, 0, 3, "B-PER", 0.9),
+            token(2, 1, 4, 9, "O", 0.9),
+            token(3, 2, 10, 12, "O", 0.9),
+            token(4, 3, 13, 15, "B-LOC", 0.9),
+            token(5, 3, 15, 19, "I-LOC", 0.9),
+        ];
+        let entities = aggregate(
+            text,
+            &tokens,
+            &id2label(),
+            AggregationStrategy::First,
+            &["O".to_string()],
+        );
+        assert_eq!(
+            spans(&entities),
+            [("PER", "Zoë", 0, 3), ("LOC", "Zürich", 13, 19)]
+        );
+    }
+}
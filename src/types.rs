@@ -1,9 +1,130 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct ClassificationRequest {
     pub model: String,
-    pub input: Vec<String>,
+    /// A single string, a list of strings, or a list of `{text, text_pair}` objects
+    #[serde(deserialize_with = "deserialize_input")]
+    pub input: Vec<TextInput>,
+    /// Maximum time in milliseconds the request may wait for a result
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    #[serde(flatten)]
+    pub options: ClassificationOptions,
+}
+
+/// Request options controlling what each classification result contains.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ClassificationOptions {
+    /// Return the `top_k` most likely labels with their scores
+    #[serde(default)]
+    pub top_k: Option<usize>,
+    /// Return every label with its score
+    #[serde(default)]
+    pub return_all_scores: bool,
+    /// Return the raw model outputs alongside the scores
+    #[serde(default)]
+    pub return_logits: bool,
+    /// Function turning logits into scores; defaults to the one matching the
+    /// model's problem type
+    #[serde(default)]
+    pub function_to_apply: Option<FunctionToApply>,
+    /// Split inputs longer than the model's maximum sequence length into
+    /// overlapping windows and combine their results with this strategy,
+    /// instead of truncating them
+    #[serde(default)]
+    pub chunk_aggregation: Option<ChunkAggregation>,
+    /// What to do with inputs longer than the model's maximum sequence length
+    #[serde(default)]
+    pub truncation: Truncation,
+}
+
+/// Handling of inputs that don't fit in the model's maximum sequence length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Truncation {
+    /// Classify the start of the input and flag the result as `truncated`
+    #[default]
+    Truncate,
+    /// Reject the request
+    Error,
+}
+
+/// How the results of the windows of a chunked input are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChunkAggregation {
+    /// Average of the chunk scores
+    Mean,
+    /// Highest score of each label across chunks
+    Max,
+    /// Scores of the first chunk only
+    First,
+    /// Scores of the most confident chunk predicting a label other than the
+    /// first (negative) one; for multi-label models, any label on in a chunk
+    AnyPositive,
+}
+
+/// Function applied to the logits of each input, as in the Hugging Face
+/// text classification pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FunctionToApply {
+    Softmax,
+    Sigmoid,
+    /// Scores are the raw logits
+    None,
+}
+
+/// One text to classify, optionally paired with a second segment.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TextInput {
+    pub text: String,
+    #[serde(default)]
+    pub text_pair: Option<String>,
+}
+
+impl From<String> for TextInput {
+    fn from(text: String) -> Self {
+        Self {
+            text,
+            text_pair: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum InputItem {
+    Text(String),
+    Object(TextInput),
+}
+
+#[derive(Deserialize)]
+#[serde(
+    untagged,
+    expecting = "expected a string, a list of strings, or a list of {text, text_pair} objects"
+)]
+enum Input {
+    Single(String),
+    List(Vec<InputItem>),
+}
+
+fn deserialize_input<'de, D>(deserializer: D) -> Result<Vec<TextInput>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let input = match Input::deserialize(deserializer)? {
+        Input::Single(text) => vec![text.into()],
+        Input::List(items) => items
+            .into_iter()
+            .map(|item| match item {
+                InputItem::Text(text) => text.into(),
+                InputItem::Object(input) => input,
+            })
+            .collect(),
+    };
+    Ok(input)
 }
 
 #[derive(Debug, Serialize)]
@@ -19,9 +140,163 @@ pub struct ClassificationResponse {
 #[derive(Debug, Serialize)]
 pub struct ClassificationData {
     pub index: usize,
-    pub label: String,
-    pub probs: Vec<f64>,
+    /// Predicted label, for classification models
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Every label above the threshold, for multi-label models
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub labels: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probs: Option<Vec<f64>>,
+    /// Labels with their probability, most likely first, when requested with
+    /// `top_k` or `return_all_scores`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scores: Option<Vec<LabelScore>>,
+    /// Raw output, for regression models
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
     pub num_classes: usize,
+    /// Number of tokens the input encodes to, including special tokens
+    pub num_tokens: usize,
+    /// Whether the input was cut off at the maximum sequence length
+    pub truncated: bool,
+    /// Number of windows the input was split into, when chunking is requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<usize>,
+    /// Raw model outputs, when requested with `return_logits`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logits: Option<Vec<f64>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LabelScore {
+    pub label: String,
+    pub score: f64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ZeroShotRequest {
+    pub model: String,
+    /// A single string or a list of strings
+    #[serde(deserialize_with = "deserialize_texts")]
+    pub input: Vec<String>,
+    pub candidate_labels: Vec<String>,
+    /// Hypothesis for each candidate label, with `{}` replaced by the label
+    #[serde(default = "default_hypothesis_template")]
+    pub hypothesis_template: String,
+    /// Score each label independently instead of normalizing across labels
+    #[serde(default)]
+    pub multi_label: bool,
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+}
+
+fn default_hypothesis_template() -> String {
+    "This example is {}.".to_string()
+}
+
+#[derive(Deserialize)]
+#[serde(untagged, expecting = "expected a string or a list of strings")]
+enum Texts {
+    Single(String),
+    List(Vec<String>),
+}
+
+fn deserialize_texts<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match Texts::deserialize(deserializer)? {
+        Texts::Single(text) => vec![text],
+        Texts::List(texts) => texts,
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct ZeroShotResponse {
+    pub id: String,
+    pub object: String,
+    pub created: i64,
+    pub model: String,
+    pub data: Vec<ZeroShotData>,
+    pub usage: Usage,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ZeroShotData {
+    pub index: usize,
+    /// Candidate labels, most likely first
+    pub labels: Vec<String>,
+    pub scores: Vec<f64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenClassificationRequest {
+    pub model: String,
+    /// A single string or a list of strings
+    #[serde(deserialize_with = "deserialize_texts")]
+    pub input: Vec<String>,
+    #[serde(default)]
+    pub aggregation_strategy: AggregationStrategy,
+    /// Labels left out of the results, `O` (outside any entity) by default
+    #[serde(default = "default_ignore_labels")]
+    pub ignore_labels: Vec<String>,
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+}
+
+fn default_ignore_labels() -> Vec<String> {
+    vec!["O".to_string()]
+}
+
+/// How per-token predictions are grouped into entities, following the Hugging
+/// Face token classification pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AggregationStrategy {
+    /// One result per token, with its own label
+    #[default]
+    None,
+    /// Adjacent tokens with the same entity type (`B-`/`I-` tags) are merged
+    Simple,
+    /// Words take the label of their first token, then are merged as `simple`
+    First,
+    /// Words take the label of their highest scoring token, then are merged as `simple`
+    Max,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenClassificationResponse {
+    pub id: String,
+    pub object: String,
+    pub created: i64,
+    pub model: String,
+    pub data: Vec<TokenClassificationData>,
+    pub usage: Usage,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenClassificationData {
+    pub index: usize,
+    pub entities: Vec<Entity>,
+}
+
+/// A labeled span of an input; `start` and `end` are character offsets.
+#[derive(Debug, Clone, Serialize)]
+pub struct Entity {
+    /// Token label, set when results are not aggregated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entity: Option<String>,
+    /// Entity type without its `B-`/`I-` prefix, set when results are aggregated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entity_group: Option<String>,
+    pub score: f64,
+    pub word: String,
+    pub start: usize,
+    pub end: usize,
+    /// Position of the token in the encoded input, set when results are not aggregated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
 }
 
 #[derive(Debug, Serialize)]
@@ -29,5 +304,99 @@ pub struct Usage {
     pub prompt_tokens: u32,
     pub total_tokens: u32,
     pub completion_tokens: u32,
-    pub prompt_tokens_details: Option<serde_json::Value>,
+    pub prompt_tokens_details: Option<PromptTokensDetails>,
+}
+
+impl Usage {
+    /// Usage of a request that only consumes prompt tokens.
+    pub fn prompt(prompt_tokens: u32, truncated_tokens: u32) -> Self {
+        Self {
+            prompt_tokens,
+            total_tokens: prompt_tokens,
+            completion_tokens: 0,
+            prompt_tokens_details: Some(PromptTokensDetails { truncated_tokens }),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PromptTokensDetails {
+    /// Tokens of inputs cut off at the maximum sequence length, not part of `prompt_tokens`
+    pub truncated_tokens: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(input: serde_json::Value) -> Result<ClassificationRequest, serde_json::Error> {
+        serde_json::from_value(json!({
+            "model": "m",
+            "input": input,
+            "top_k": 2,
+            "truncation": "error",
+        }))
+    }
+
+    /// Text and text pair of each input.
+    fn texts(request: &ClassificationRequest) -> Vec<(&str, Option<&str>)> {
+        request
+            .input
+            .iter()
+            .map(|input| (input.text.as_str(), input.text_pair.as_deref()))
+            .collect()
+    }
+
+    #[test]
+    fn input_may_be_a_single_string() {
+        let request = parse(json!("hello")).unwrap();
+        assert_eq!(texts(&request), [("hello", None)]);
+    }
+
+    #[test]
+    fn input_may_be_a_list_of_strings() {
+        let request = parse(json!(["a", "b"])).unwrap();
+        assert_eq!(texts(&request), [("a", None), ("b", None)]);
+    }
+
+    #[test]
+    fn input_may_be_a_list_of_objects() {
+        let request = parse(json!([
+            {"text": "premise", "text_pair": "hypothesis"},
+            {"text": "alone"},
+        ]))
+        .unwrap();
+        assert_eq!(
+            texts(&request),
+            [("premise", Some("hypothesis")), ("alone", None)]
+        );
+    }
+
+    #[test]
+    fn input_may_mix_strings_and_objects() {
+        let request = parse(json!(["a", {"text": "b", "text_pair": "c"}])).unwrap();
+        assert_eq!(texts(&request), [("a", None), ("b", Some("c"))]);
+    }
+
+    #[test]
+    fn options_are_read_alongside_the_input() {
+        let request = parse(json!(["a"])).unwrap();
+        assert_eq!(request.options.top_k, Some(2));
+        assert_eq!(request.options.truncation, Truncation::Error);
+        assert!(!request.options.return_logits);
+    }
+
+    #[test]
+    fn invalid_inputs_are_rejected() {
+        for input in [
+            json!(1),
+            json!([1]),
+            json!({"text": "a"}),
+            json!([{"text_pair": "b"}]),
+            json!(null),
+        ] {
+            assert!(parse(input.clone()).is_err(), "accepted {input}");
+        }
+    }
 }
@@ -0,0 +1,147 @@
+use crate::config::RequestLimits;
+use crate::error::ApiError;
+use crate::types::TextInput;
+
+/// Checks a request's inputs against the configured limits, naming the
+/// offending input in the error's `param`.
+pub fn validate_inputs(inputs: &[TextInput], limits: &RequestLimits) -> Result<(), ApiError> {
+    if inputs.is_empty() {
+        return Err(
+            ApiError::bad_request("`input` must contain at least one text")
+                .with_code("empty_input")
+                .with_param("input"),
+        );
+    }
+
+    if inputs.len() > limits.max_inputs {
+        return Err(ApiError::payload_too_large(format!(
+            "Request has {} inputs, the maximum is {}",
+            inputs.len(),
+            limits.max_inputs
+        ))
+        .with_code("too_many_inputs")
+        .with_param("input"));
+    }
+
+    for (index, input) in inputs.iter().enumerate() {
+        let param = || format!("input[{index}]");
+
+        let empty = input.text.is_empty() || input.text_pair.as_ref().is_some_and(String::is_empty);
+        if !limits.allow_empty_inputs && empty {
+            return Err(ApiError::bad_request(format!("Input {index} is empty"))
+                .with_code("empty_input")
+                .with_param(param()));
+        }
+
+        if let Some(max_chars) = limits.max_input_chars {
+            let chars = input.text.chars().count()
+                + input
+                    .text_pair
+                    .as_ref()
+                    .map_or(0, |pair| pair.chars().count());
+            if chars > max_chars {
+                return Err(ApiError::bad_request(format!(
+                    "Input {index} has {chars} characters, the maximum is {max_chars}"
+                ))
+                .with_code("input_too_long")
+                .with_param(param()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+    use serde_json::Value;
+
+    fn limits(max_inputs: usize, max_input_chars: Option<usize>) -> RequestLimits {
+        RequestLimits {
+            max_inputs,
+            max_input_chars,
+            allow_empty_inputs: false,
+        }
+    }
+
+    fn inputs(texts: &[&str]) -> Vec<TextInput> {
+        texts.iter().map(|text| text.to_string().into()).collect()
+    }
+
+    /// Status and `error` object of the response an error is returned as.
+    async fn rejection(result: Result<(), ApiError>) -> (StatusCode, Value) {
+        let response = result
+            .expect_err("inputs passed validation")
+            .into_response();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        (status, body["error"].clone())
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_without_inputs() {
+        let (status, error) = rejection(validate_inputs(&[], &limits(8, None))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error["code"], "empty_input");
+        assert_eq!(error["param"], "input");
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_with_too_many_inputs() {
+        assert!(validate_inputs(&inputs(&["a", "b"]), &limits(2, None)).is_ok());
+
+        let (status, error) =
+            rejection(validate_inputs(&inputs(&["a", "b", "c"]), &limits(2, None))).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(error["code"], "too_many_inputs");
+        assert_eq!(error["param"], "input");
+    }
+
+    #[tokio::test]
+    async fn rejects_inputs_longer_than_max_input_chars() {
+        // Characters are counted, not bytes, and the text pair counts too
+        let pair = [TextInput {
+            text: "héllo".to_string(),
+            text_pair: Some("wörld".to_string()),
+        }];
+        assert!(validate_inputs(&pair, &limits(8, Some(10))).is_ok());
+
+        let (status, error) = rejection(validate_inputs(&pair, &limits(8, Some(9)))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error["code"], "input_too_long");
+        assert_eq!(error["param"], "input[0]");
+    }
+
+    #[tokio::test]
+    async fn names_the_offending_input() {
+        let (status, error) =
+            rejection(validate_inputs(&inputs(&["a", "b", ""]), &limits(8, None))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error["code"], "empty_input");
+        assert_eq!(error["param"], "input[2]");
+
+        let (_, error) = rejection(validate_inputs(
+            &inputs(&["short", "too long"]),
+            &limits(8, Some(5)),
+        ))
+        .await;
+        assert_eq!(error["param"], "input[1]");
+    }
+
+    #[test]
+    fn empty_inputs_may_be_allowed() {
+        let limits = RequestLimits {
+            allow_empty_inputs: true,
+            ..limits(8, None)
+        };
+        assert!(validate_inputs(&inputs(&["a", ""]), &limits).is_ok());
+        // An empty list is still rejected
+        assert!(validate_inputs(&[], &limits).is_err());
+    }
+}
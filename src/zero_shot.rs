@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+
+use crate::engine::EngineError;
+use crate::types::{ClassificationData, TextInput, ZeroShotData};
+
+/// Positions of the NLI classes in a model's logits.
+#[derive(Debug, Clone, Copy)]
+pub struct NliLabels {
+    entailment: usize,
+    contradiction: usize,
+}
+
+impl NliLabels {
+    /// Finds the entailment and contradiction classes by label name. Returns
+    /// `None` for models without an entailment label, which can't be used for
+    /// zero-shot classification.
+    pub fn from_id2label(id2label: &HashMap<u32, String>) -> Option<Self> {
+        let find = |prefix: &str| {
+            id2label
+                .iter()
+                .filter(|(_, label)| label.to_lowercase().starts_with(prefix))
+                .map(|(&id, _)| id as usize)
+                .min()
+        };
+
+        let entailment = find("entail")?;
+        // Same fallback as the Hugging Face pipeline when no label is named
+        // "contradiction": the first class, unless that is entailment.
+        let contradiction = find("contra").unwrap_or(if entailment == 0 {
+            id2label.len().saturating_sub(1)
+        } else {
+            0
+        });
+
+        Some(Self {
+            entailment,
+            contradiction,
+        })
+    }
+}
+
+/// Premise/hypothesis pairs for every text and candidate label, grouped by text.
+pub fn expand_pairs(texts: &[String], labels: &[String], template: &str) -> Vec<TextInput> {
+    texts
+        .iter()
+        .flat_map(|text| {
+            labels.iter().map(move |label| TextInput {
+                text: text.clone(),
+                text_pair: Some(template.replace("{}", label)),
+            })
+        })
+        .collect()
+}
+
+/// Points an error about one of the pairs from [`expand_pairs`] at the text
+/// it was built from.
+pub fn pair_error(err: anyhow::Error, label_count: usize) -> anyhow::Error {
+    match err.downcast::<EngineError>() {
+        Ok(EngineError::InvalidInput { index, message }) => EngineError::InvalidInput {
+            index: index / label_count,
+            message,
+        }
+        .into(),
+        Ok(err) => err.into(),
+        Err(err) => err,
+    }
+}
+
+fn logit(pair: &ClassificationData, class: usize) -> f64 {
+    pair.logits
+        .as_ref()
+        .expect("zero-shot pairs are classified with `return_logits`")[class]
+}
+
+/// Scores the candidate labels of one text from the results of its pairs, in
+/// label order. In single-label mode the entailment logits are normalized
+/// across labels; in multi-label mode each label gets the probability of
+/// entailment over contradiction for its own pair.
+fn score_labels(pairs: &[ClassificationData], nli: NliLabels, multi_label: bool) -> Vec<f64> {
+    if multi_label || pairs.len() == 1 {
+        pairs
+            .iter()
+            .map(|pair| {
+                let entailment = logit(pair, nli.entailment);
+                let contradiction = logit(pair, nli.contradiction);
+                1.0 / (1.0 + (contradiction - entailment).exp())
+            })
+            .collect()
+    } else {
+        let entailment: Vec<f64> = pairs
+            .iter()
+            .map(|pair| logit(pair, nli.entailment))
+            .collect();
+        let max = entailment.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let exp: Vec<f64> = entailment.iter().map(|logit| (logit - max).exp()).collect();
+        let sum: f64 = exp.iter().sum();
+        exp.into_iter().map(|value| value / sum).collect()
+    }
+}
+
+/// Folds the pair results produced from [`expand_pairs`] back into one
+/// ranking of the candidate labels per text.
+pub fn rank_labels(
+    pairs: &[ClassificationData],
+    labels: &[String],
+    nli: NliLabels,
+    multi_label: bool,
+) -> Vec<ZeroShotData> {
+    pairs
+        .chunks(labels.len())
+        .enumerate()
+        .map(|(index, text_pairs)| {
+            let mut ranked: Vec<(String, f64)> = labels
+                .iter()
+                .cloned()
+                .zip(score_labels(text_pairs, nli, multi_label))
+                .collect();
+            ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+            let (labels, scores) = ranked.into_iter().unzip();
+
+            ZeroShotData {
+                index,
+                labels,
+                scores,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Class order of MNLI models: contradiction, neutral, entailment.
+    const NLI: NliLabels = NliLabels {
+        entailment: 2,
+        contradiction: 0,
+    };
+
+    fn id2label(labels: &[&str]) -> HashMap<u32, String> {
+        labels
+            .iter()
+            .enumerate()
+            .map(|(id, label)| (id as u32, label.to_string()))
+            .collect()
+    }
+
+    fn labels(names: &[&str]) -> Vec<String> {
+        names.iter().map(ToString::to_string).collect()
+    }
+
+    /// The result of a pair with the given contradiction and entailment logits.
+    fn pair(index: usize, contradiction: f64, entailment: f64) -> ClassificationData {
+        ClassificationData {
+            index,
+            label: None,
+            labels: None,
+            probs: None,
+            scores: None,
+            score: None,
+            num_classes: 3,
+            num_tokens: 8,
+            truncated: false,
+            chunks: None,
+            logits: Some(vec![contradiction, 0.0, entailment]),
+        }
+    }
+
+    fn sigmoid(x: f64) -> f64 {
+        1.0 / (1.0 + (-x).exp())
+    }
+
+    fn assert_close(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len(), "{actual:?} != {expected:?}");
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-9, "{actual:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn finds_nli_classes_by_name() {
+        let nli = NliLabels::from_id2label(&id2label(&["CONTRADICTION", "neutral", "ENTAILMENT"]))
+            .unwrap();
+        assert_eq!((nli.entailment, nli.contradiction), (2, 0));
+
+        let nli = NliLabels::from_id2label(&id2label(&["entailment", "neutral", "contradiction"]))
+            .unwrap();
+        assert_eq!((nli.entailment, nli.contradiction), (0, 2));
+    }
+
+    #[test]
+    fn contradiction_falls_back_to_the_first_class_other_than_entailment() {
+        let nli = NliLabels::from_id2label(&id2label(&["neutral", "entailment"])).unwrap();
+        assert_eq!((nli.entailment, nli.contradiction), (1, 0));
+
+        let nli = NliLabels::from_id2label(&id2label(&["entailment", "not_entailment"])).unwrap();
+        assert_eq!((nli.entailment, nli.contradiction), (0, 1));
+    }
+
+    #[test]
+    fn models_without_entailment_are_not_nli_models() {
+        assert!(NliLabels::from_id2label(&id2label(&["negative", "positive"])).is_none());
+    }
+
+    #[test]
+    fn single_label_scores_are_normalized_across_labels() {
+        // Contradiction logits are ignored when normalizing across labels
+        let pairs = [pair(0, 5.0, 2.0), pair(1, -5.0, 0.0)];
+        let scores = score_labels(&pairs, NLI, false);
+
+        let e = 2f64.exp();
+        assert_close(&scores, &[e / (e + 1.0), 1.0 / (e + 1.0)]);
+    }
+
+    #[test]
+    fn multi_label_scores_each_label_against_contradiction() {
+        let pairs = [pair(0, 5.0, 2.0), pair(1, -5.0, 0.0)];
+        let scores = score_labels(&pairs, NLI, true);
+
+        assert_close(&scores, &[sigmoid(2.0 - 5.0), sigmoid(5.0)]);
+    }
+
+    #[test]
+    fn a_single_label_is_scored_against_contradiction() {
+        // Normalizing over one label would always give it a score of 1
+        let pairs = [pair(0, 1.0, 0.0)];
+        assert_close(&score_labels(&pairs, NLI, false), &[sigmoid(-1.0)]);
+        assert_close(&score_labels(&pairs, NLI, true), &[sigmoid(-1.0)]);
+    }
+
+    #[test]
+    fn ranks_the_labels_of_each_text() {
+        let labels = labels(&["sports", "politics"]);
+        let pairs = [
+            pair(0, 0.0, 1.0),
+            pair(1, 0.0, 3.0),
+            pair(2, 0.0, 2.0),
+            pair(3, 0.0, -1.0),
+        ];
+
+        let ranked = rank_labels(&pairs, &labels, NLI, false);
+
+        assert_eq!(ranked.len(), 2);
+        assert_eq!(ranked[0].index, 0);
+        assert_eq!(ranked[0].labels, ["politics", "sports"]);
+        let e = 2f64.exp();
+        assert_close(&ranked[0].scores, &[e / (e + 1.0), 1.0 / (e + 1.0)]);
+        assert_eq!(ranked[1].index, 1);
+        assert_eq!(ranked[1].labels, ["sports", "politics"]);
+        assert!((ranked[1].scores.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn expands_a_pair_per_text_and_label() {
+        let pairs = expand_pairs(&labels(&["a", "b"]), &labels(&["x", "y"]), "About {}.");
+        let texts: Vec<_> = pairs
+            .iter()
+            .map(|pair| (pair.text.as_str(), pair.text_pair.as_deref().unwrap()))
+            .collect();
+        assert_eq!(
+            texts,
+            [
+                ("a", "About x."),
+                ("a", "About y."),
+                ("b", "About x."),
+                ("b", "About y.")
+            ]
+        );
+    }
+
+    #[test]
+    fn pair_errors_point_at_their_text() {
+        let err = EngineError::InvalidInput {
+            index: 5,
+            message: "too long".to_string(),
+        };
+        let err = pair_error(err.into(), 3);
+        assert!(matches!(
+            err.downcast_ref::<EngineError>(),
+            Some(EngineError::InvalidInput { index: 1, message }) if message == "too long"
+        ));
+
+        let err = pair_error(EngineError::Overloaded.into(), 3);
+        assert!(matches!(
+            err.downcast_ref::<EngineError>(),
+            Some(EngineError::Overloaded)
+        ));
+
+        let err = pair_error(anyhow::anyhow!("model failed"), 3);
+        assert_eq!(err.to_string(), "model failed");
+    }
+}